@@ -89,7 +89,7 @@ like id =
     "/oration/like"
         |> HttpBuilder.post
         |> HttpBuilder.withQueryParams [ ( "id", toString id ) ]
-        |> HttpBuilder.withExpect (Http.expectStringResponse (\response -> Ok (Result.withDefault -1 (String.toInt response.body))))
+        |> HttpBuilder.withExpect (Http.expectJson (Decode.field "id" Decode.int))
         |> HttpBuilder.toRequest
 
 
@@ -102,7 +102,7 @@ dislike id =
     "/oration/dislike"
         |> HttpBuilder.post
         |> HttpBuilder.withQueryParams [ ( "id", toString id ) ]
-        |> HttpBuilder.withExpect (Http.expectStringResponse (\response -> Ok (Result.withDefault -1 (String.toInt response.body))))
+        |> HttpBuilder.withExpect (Http.expectJson (Decode.field "id" Decode.int))
         |> HttpBuilder.toRequest
 
 
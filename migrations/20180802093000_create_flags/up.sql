@@ -0,0 +1,8 @@
+CREATE TABLE flags (
+    id INTEGER PRIMARY KEY NOT NULL,
+    cid INTEGER NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+    remote_addr VARCHAR NOT NULL,
+    reason VARCHAR NOT NULL,
+    created DATETIME NOT NULL,
+    UNIQUE (cid, remote_addr)
+);
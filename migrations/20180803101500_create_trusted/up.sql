@@ -0,0 +1,4 @@
+CREATE TABLE trusted (
+    hash VARCHAR PRIMARY KEY NOT NULL,
+    created DATETIME NOT NULL
+);
@@ -0,0 +1,6 @@
+CREATE TABLE emails (
+    email VARCHAR PRIMARY KEY NOT NULL,
+    token VARCHAR UNIQUE,
+    verified BOOLEAN NOT NULL DEFAULT 0,
+    created DATETIME NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE threads_backup (
+    id INTEGER PRIMARY KEY NOT NULL,
+    uri VARCHAR(256) UNIQUE NOT NULL,
+    title VARCHAR(256)
+);
+INSERT INTO threads_backup SELECT id, uri, title FROM threads;
+DROP TABLE threads;
+ALTER TABLE threads_backup RENAME TO threads;
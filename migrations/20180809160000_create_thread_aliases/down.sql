@@ -0,0 +1,2 @@
+DROP TRIGGER remove_stale_aliases;
+DROP TABLE thread_aliases;
@@ -0,0 +1,8 @@
+CREATE TABLE thread_aliases (
+    uri VARCHAR(256) PRIMARY KEY NOT NULL,
+    tid INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER remove_stale_aliases AFTER DELETE ON threads BEGIN
+    DELETE FROM thread_aliases WHERE tid = OLD.id;
+END;
@@ -0,0 +1,26 @@
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TEMPORARY TABLE thread_aliases_backup AS SELECT uri, tid FROM thread_aliases WHERE site = '';
+DROP TABLE thread_aliases;
+
+CREATE TABLE threads_new (
+    id INTEGER PRIMARY KEY NOT NULL,
+    uri VARCHAR(256) UNIQUE NOT NULL,
+    title VARCHAR(256),
+    closed BOOLEAN NOT NULL DEFAULT 0
+);
+INSERT INTO threads_new (id, uri, title, closed) SELECT id, uri, title, closed FROM threads WHERE site = '';
+DELETE FROM comments WHERE tid NOT IN (SELECT id FROM threads_new);
+DROP TABLE threads;
+ALTER TABLE threads_new RENAME TO threads;
+
+CREATE TABLE thread_aliases (
+    uri VARCHAR(256) PRIMARY KEY NOT NULL,
+    tid INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE
+);
+INSERT INTO thread_aliases (uri, tid) SELECT uri, tid FROM thread_aliases_backup;
+DROP TABLE thread_aliases_backup;
+
+CREATE TRIGGER remove_stale_aliases AFTER DELETE ON threads BEGIN
+    DELETE FROM thread_aliases WHERE tid = OLD.id;
+END;
@@ -0,0 +1,32 @@
+-- Threads are now unique per site rather than per uri, which requires rebuilding the table.
+-- Foreign key checks are deferred until the rebuilt tables are back in place, but aliases
+-- must be stashed since dropping threads would otherwise cascade to them.
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TEMPORARY TABLE thread_aliases_backup AS SELECT uri, tid FROM thread_aliases;
+DROP TABLE thread_aliases;
+
+CREATE TABLE threads_new (
+    id INTEGER PRIMARY KEY NOT NULL,
+    site VARCHAR NOT NULL DEFAULT '',
+    uri VARCHAR(256) NOT NULL,
+    title VARCHAR(256),
+    closed BOOLEAN NOT NULL DEFAULT 0,
+    UNIQUE (site, uri)
+);
+INSERT INTO threads_new (id, site, uri, title, closed) SELECT id, '', uri, title, closed FROM threads;
+DROP TABLE threads;
+ALTER TABLE threads_new RENAME TO threads;
+
+CREATE TABLE thread_aliases (
+    site VARCHAR NOT NULL DEFAULT '',
+    uri VARCHAR(256) NOT NULL,
+    tid INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE,
+    PRIMARY KEY (site, uri)
+);
+INSERT INTO thread_aliases (site, uri, tid) SELECT '', uri, tid FROM thread_aliases_backup;
+DROP TABLE thread_aliases_backup;
+
+CREATE TRIGGER remove_stale_aliases AFTER DELETE ON threads BEGIN
+    DELETE FROM thread_aliases WHERE tid = OLD.id;
+END;
@@ -0,0 +1,13 @@
+-- Voters are identified by a salted hash of their IP address. Votes cast before this table
+-- existed stay in the comment tallies, but the bloom filters in comments.voters cannot be
+-- read back into voter identities. They are kept so that those voters cannot vote twice.
+CREATE TABLE votes (
+    cid INTEGER NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+    voter VARCHAR NOT NULL,
+    upvote BOOLEAN NOT NULL,
+    created DATETIME NOT NULL,
+    PRIMARY KEY (cid, voter)
+);
+
+UPDATE comments SET likes = 0 WHERE likes IS NULL;
+UPDATE comments SET dislikes = 0 WHERE dislikes IS NULL;
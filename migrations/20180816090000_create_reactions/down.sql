@@ -0,0 +1,7 @@
+DROP TRIGGER remove_stale_threads;
+CREATE TRIGGER remove_stale_threads AFTER DELETE ON comments BEGIN
+    DELETE FROM threads WHERE id NOT IN (SELECT tid FROM comments);
+END;
+
+DROP TABLE thread_reactions;
+DROP TABLE comment_reactions;
@@ -0,0 +1,23 @@
+CREATE TABLE comment_reactions (
+    cid INTEGER NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+    voter VARCHAR NOT NULL,
+    reaction VARCHAR NOT NULL,
+    created DATETIME NOT NULL,
+    PRIMARY KEY (cid, voter, reaction)
+);
+
+CREATE TABLE thread_reactions (
+    tid INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE,
+    voter VARCHAR NOT NULL,
+    reaction VARCHAR NOT NULL,
+    created DATETIME NOT NULL,
+    PRIMARY KEY (tid, voter, reaction)
+);
+
+-- Threads can now be reacted to before anyone comments on them, so they are only stale once
+-- they have neither comments nor reactions.
+DROP TRIGGER remove_stale_threads;
+CREATE TRIGGER remove_stale_threads AFTER DELETE ON comments BEGIN
+    DELETE FROM threads WHERE id NOT IN (SELECT tid FROM comments)
+        AND id NOT IN (SELECT tid FROM thread_reactions);
+END;
@@ -0,0 +1,5 @@
+DROP TRIGGER index_changed_titles;
+DROP TRIGGER unindex_removed_comments;
+DROP TRIGGER index_changed_comments;
+DROP TRIGGER index_new_comments;
+DROP TABLE comment_search;
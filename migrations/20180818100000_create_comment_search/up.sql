@@ -0,0 +1,29 @@
+-- Full text index of comments. Each row shares its rowid with the comment it indexes, and is
+-- kept in sync with the comments and threads tables by the triggers below.
+CREATE VIRTUAL TABLE comment_search USING fts5(text, author, title);
+
+INSERT INTO comment_search(rowid, text, author, title)
+    SELECT comments.id, comments.text, comments.author, threads.title
+    FROM comments INNER JOIN threads ON threads.id = comments.tid;
+
+CREATE TRIGGER index_new_comments AFTER INSERT ON comments BEGIN
+    INSERT INTO comment_search(rowid, text, author, title)
+        VALUES (NEW.id, NEW.text, NEW.author, (SELECT title FROM threads WHERE id = NEW.tid));
+END;
+
+CREATE TRIGGER index_changed_comments AFTER UPDATE OF text, author, tid ON comments BEGIN
+    UPDATE comment_search SET
+        text = NEW.text,
+        author = NEW.author,
+        title = (SELECT title FROM threads WHERE id = NEW.tid)
+    WHERE rowid = NEW.id;
+END;
+
+CREATE TRIGGER unindex_removed_comments AFTER DELETE ON comments BEGIN
+    DELETE FROM comment_search WHERE rowid = OLD.id;
+END;
+
+CREATE TRIGGER index_changed_titles AFTER UPDATE OF title ON threads BEGIN
+    UPDATE comment_search SET title = NEW.title
+    WHERE rowid IN (SELECT id FROM comments WHERE tid = NEW.id);
+END;
@@ -0,0 +1,13 @@
+CREATE TABLE comment_revisions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    cid INTEGER NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+    text VARCHAR NOT NULL,
+    author VARCHAR,
+    email VARCHAR,
+    website VARCHAR,
+    hash VARCHAR NOT NULL,
+    created DATETIME NOT NULL,
+    replaced DATETIME NOT NULL
+);
+
+CREATE INDEX comment_revisions_cid ON comment_revisions (cid);
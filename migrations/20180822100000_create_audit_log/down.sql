@@ -0,0 +1,2 @@
+DROP TABLE comment_trash;
+DROP TABLE audit_log;
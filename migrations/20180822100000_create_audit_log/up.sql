@@ -0,0 +1,22 @@
+-- Records who deleted or restored which comment. Entries outlive the comments they describe,
+-- so they hold a snapshot of the comment rather than a foreign key.
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY NOT NULL,
+    cid INTEGER NOT NULL,
+    site VARCHAR NOT NULL,
+    actor VARCHAR NOT NULL,
+    action VARCHAR NOT NULL,
+    snapshot VARCHAR NOT NULL,
+    created DATETIME NOT NULL
+);
+
+CREATE INDEX audit_log_cid ON audit_log (cid);
+
+-- Deleted comments, kept for a limited time so administrators can restore them.
+CREATE TABLE comment_trash (
+    id INTEGER PRIMARY KEY NOT NULL,
+    cid INTEGER NOT NULL,
+    site VARCHAR NOT NULL,
+    snapshot VARCHAR NOT NULL,
+    deleted DATETIME NOT NULL
+);
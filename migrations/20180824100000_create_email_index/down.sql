@@ -0,0 +1,4 @@
+-- Encrypted addresses can't be recovered without the server secret, and rebuilding the
+-- comments table would cascade to everything which references it, so the columns are kept.
+DROP INDEX comment_revisions_email_index;
+DROP INDEX comments_email_index;
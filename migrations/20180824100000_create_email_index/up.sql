@@ -0,0 +1,9 @@
+-- Email addresses are now stored encrypted, alongside a blind index (a keyed hash of the
+-- normalised address) which they are looked up by. Existing addresses are encrypted by the
+-- server on its next start, as the key is not known here. The emails table is keyed by the
+-- blind index from then on.
+ALTER TABLE comments ADD COLUMN email_index VARCHAR;
+ALTER TABLE comment_revisions ADD COLUMN email_index VARCHAR;
+
+CREATE INDEX comments_email_index ON comments (email_index);
+CREATE INDEX comment_revisions_email_index ON comment_revisions (email_index);
@@ -0,0 +1 @@
+DROP TRIGGER remove_comment_dependents;
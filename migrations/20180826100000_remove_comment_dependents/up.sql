@@ -0,0 +1,13 @@
+-- Foreign key enforcement is never switched on, so deleting a comment doesn't cascade to the
+-- rows which reference it. Since comment ids are reused, those rows would otherwise be picked
+-- up by the next comment. As with thread aliases, a trigger removes them instead, once any
+-- which have already been left behind are cleared out.
+DELETE FROM flags WHERE cid NOT IN (SELECT id FROM comments);
+DELETE FROM votes WHERE cid NOT IN (SELECT id FROM comments);
+DELETE FROM comment_reactions WHERE cid NOT IN (SELECT id FROM comments);
+
+CREATE TRIGGER remove_comment_dependents AFTER DELETE ON comments BEGIN
+    DELETE FROM flags WHERE cid = OLD.id;
+    DELETE FROM votes WHERE cid = OLD.id;
+    DELETE FROM comment_reactions WHERE cid = OLD.id;
+END;
@@ -0,0 +1 @@
+UPDATE comments SET mode = 1 WHERE mode = 5;
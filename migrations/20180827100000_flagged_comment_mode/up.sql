@@ -0,0 +1,3 @@
+-- Comments held after being flagged too often now have a mode of their own, so approving
+-- them doesn't trust their commentor as approving a held first comment does.
+UPDATE comments SET mode = 5 WHERE mode = 1 AND id IN (SELECT cid FROM flags);
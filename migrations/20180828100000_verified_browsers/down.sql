@@ -0,0 +1,10 @@
+CREATE TABLE emails_old (
+    email VARCHAR PRIMARY KEY NOT NULL,
+    token VARCHAR UNIQUE,
+    verified BOOLEAN NOT NULL DEFAULT 0,
+    created DATETIME NOT NULL
+);
+INSERT INTO emails_old (email, token, verified, created)
+    SELECT email, MAX(token), MAX(verified), MIN(created) FROM emails GROUP BY email;
+DROP TABLE emails;
+ALTER TABLE emails_old RENAME TO emails;
@@ -0,0 +1,17 @@
+-- Anyone can type in a verified commentor's email address, so verifying an address now only
+-- vouches for the browser it was requested from, identified by a random token the browser
+-- keeps. Addresses are now stored once for each such browser. Those verified beforehand
+-- stay verified for reply notifications, but their commentors need to verify them again
+-- before their comments skip the verification hold.
+CREATE TABLE emails_new (
+    email VARCHAR NOT NULL,
+    browser VARCHAR NOT NULL DEFAULT '',
+    token VARCHAR UNIQUE,
+    verified BOOLEAN NOT NULL DEFAULT 0,
+    created DATETIME NOT NULL,
+    PRIMARY KEY (email, browser)
+);
+INSERT INTO emails_new (email, browser, token, verified, created)
+    SELECT email, '', token, verified, created FROM emails;
+DROP TABLE emails;
+ALTER TABLE emails_new RENAME TO emails;
@@ -0,0 +1,34 @@
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TEMPORARY TABLE thread_aliases_backup AS SELECT site, uri, tid FROM thread_aliases;
+DROP TABLE thread_aliases;
+
+CREATE TABLE threads_backup (
+    id INTEGER PRIMARY KEY NOT NULL,
+    site VARCHAR NOT NULL DEFAULT '',
+    uri VARCHAR(256) NOT NULL,
+    title VARCHAR(256),
+    closed BOOLEAN NOT NULL DEFAULT 0,
+    UNIQUE (site, uri)
+);
+INSERT INTO threads_backup SELECT id, site, uri, title, closed FROM threads;
+DROP TABLE threads;
+ALTER TABLE threads_backup RENAME TO threads;
+
+CREATE TABLE thread_aliases (
+    site VARCHAR NOT NULL DEFAULT '',
+    uri VARCHAR(256) NOT NULL,
+    tid INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE,
+    PRIMARY KEY (site, uri)
+);
+INSERT INTO thread_aliases (site, uri, tid) SELECT site, uri, tid FROM thread_aliases_backup;
+DROP TABLE thread_aliases_backup;
+
+CREATE TRIGGER remove_stale_aliases AFTER DELETE ON threads BEGIN
+    DELETE FROM thread_aliases WHERE tid = OLD.id;
+END;
+
+CREATE TRIGGER index_changed_titles AFTER UPDATE OF title ON threads BEGIN
+    UPDATE comment_search SET title = NEW.title
+    WHERE rowid IN (SELECT id FROM comments WHERE tid = NEW.id);
+END;
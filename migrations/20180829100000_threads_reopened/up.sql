@@ -0,0 +1,3 @@
+-- Threads closed automatically after `close_after` days can be reopened by the admin, which
+-- is stored explicitly since those closures are not.
+ALTER TABLE threads ADD COLUMN reopened BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+-- The cleared bloom filters can't be recovered.
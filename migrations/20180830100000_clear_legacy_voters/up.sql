@@ -0,0 +1,5 @@
+-- Voters were recorded in a bloom filter before the votes table existed. Its false positives
+-- kept new voters from voting on older comments, and since the directions of those votes are
+-- unknown they can't be changed or retracted anyway, so the filters are dropped. The tallies
+-- of likes and dislikes are kept.
+UPDATE comments SET voters = NULL;
@@ -0,0 +1 @@
+-- Removed revisions can't be brought back.
@@ -0,0 +1,3 @@
+-- Revisions of comments which were removed entirely were left behind, and would be picked up
+-- by the next comment given the same id.
+DELETE FROM comment_revisions WHERE cid NOT IN (SELECT id FROM comments);
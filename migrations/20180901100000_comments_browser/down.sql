@@ -0,0 +1,3 @@
+-- Rebuilding the comments table would cascade to everything which references it, so the
+-- column is kept, but no longer tells held comments apart.
+UPDATE comments SET browser = NULL;
@@ -0,0 +1,5 @@
+-- A comment held for verification is only published once the address is verified from the
+-- browser it was sent from, identified as in the emails table. Comments held beforehand were
+-- sent before browsers were told apart, so are published by the links sent at the time.
+ALTER TABLE comments ADD COLUMN browser VARCHAR;
+UPDATE comments SET browser = '' WHERE mode = 4;
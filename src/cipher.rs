@@ -0,0 +1,112 @@
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rand::{OsRng, RngCore};
+
+use errors::*;
+use models::emails::normalise;
+
+/// Marks a value which has been encrypted, rather than stored before encryption was introduced.
+pub const SEALED: &str = "sealed:";
+/// Length of the random nonce stored with each encrypted value.
+const NONCE_LENGTH: usize = 12;
+/// Length of the authentication tag stored with each encrypted value.
+const TAG_LENGTH: usize = 16;
+
+/// Encrypts personal data before it is stored, and computes blind indexes of email addresses
+/// so they can still be looked up without being decrypted. Both keys are derived from the
+/// server secret, so changing it leaves everything already encrypted unreadable.
+pub struct Cipher {
+    /// AES-256-GCM key used to encrypt values.
+    key: [u8; 32],
+    /// HMAC-SHA256 key used for blind indexes.
+    index_key: [u8; 32],
+}
+
+impl Cipher {
+    /// Derives the keys of the cipher from the server `secret`.
+    pub fn new(secret: &str) -> Cipher {
+        let mut prk = [0u8; 32];
+        hkdf_extract(Sha256::new(), b"oration", secret.as_bytes(), &mut prk);
+        let mut key = [0u8; 32];
+        hkdf_expand(Sha256::new(), &prk, b"encryption", &mut key);
+        let mut index_key = [0u8; 32];
+        hkdf_expand(Sha256::new(), &prk, b"blind index", &mut index_key);
+        Cipher { key, index_key }
+    }
+
+    /// Encrypts `plain` for storage. Each call uses a fresh nonce, so equal values are stored
+    /// differently and can only be matched via their blind index.
+    pub fn encrypt(&self, plain: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        OsRng::new()
+            .chain_err(|| ErrorKind::Rand)?
+            .fill_bytes(&mut nonce);
+        let mut sealed = vec![0u8; plain.len()];
+        let mut tag = [0u8; TAG_LENGTH];
+        AesGcm::new(KeySize::KeySize256, &self.key, &nonce, &[]).encrypt(
+            plain.as_bytes(),
+            &mut sealed,
+            &mut tag,
+        );
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&sealed);
+        bytes.extend_from_slice(&tag);
+        Ok(format!("{}{}", SEALED, to_hex(&bytes)))
+    }
+
+    /// Decrypts a value stored by `encrypt`. Values stored before encryption was introduced
+    /// are returned as they are.
+    pub fn decrypt(&self, stored: &str) -> Result<String> {
+        if !is_sealed(stored) {
+            return Ok(stored.to_string());
+        }
+        let bytes = from_hex(&stored[SEALED.len()..])
+            .ok_or_else(|| Error::from(ErrorKind::Decrypt))?;
+        if bytes.len() < NONCE_LENGTH + TAG_LENGTH {
+            return Err(ErrorKind::Decrypt.into());
+        }
+        let (nonce, rest) = bytes.split_at(NONCE_LENGTH);
+        let (sealed, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+        let mut plain = vec![0u8; sealed.len()];
+        if !AesGcm::new(KeySize::KeySize256, &self.key, nonce, &[]).decrypt(sealed, &mut plain, tag)
+        {
+            return Err(ErrorKind::Decrypt.into());
+        }
+        String::from_utf8(plain).chain_err(|| ErrorKind::Decrypt)
+    }
+
+    /// Computes the blind index of an email address, which matches every spelling of the
+    /// address that normalises to the same value.
+    pub fn index(&self, address: &str) -> String {
+        let mut mac = Hmac::new(Sha256::new(), &self.index_key);
+        mac.input(normalise(address).as_bytes());
+        to_hex(mac.result().code())
+    }
+}
+
+/// Checks if a stored value has been encrypted.
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(SEALED)
+}
+
+/// Hex encodes `bytes`.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes a hex string, or returns `None` if it is malformed.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
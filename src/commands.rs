@@ -0,0 +1,72 @@
+use diesel::sqlite::SqliteConnection;
+use serde_json;
+
+use cipher::Cipher;
+use config::Config;
+use errors::*;
+use models::preferences::Preference;
+use models::privacy::{self, Subject};
+use models::threads;
+
+/// Reads the email address or identifier hash which a privacy command acts on.
+fn subject(args: &[String], command: &str) -> Result<Subject> {
+    match args.get(1).and_then(|value| Subject::parse(value)) {
+        Some(subject) => Ok(subject),
+        None => Err(ErrorKind::CommandUsage(format!("{} <email|hash>", command)).into()),
+    }
+}
+
+/// Stores the SMTP password given on the command line, for the site named after it or
+/// the default site.
+fn store_smtp_password(
+    args: &[String],
+    conn: &SqliteConnection,
+    config: &Config,
+    cipher: &Cipher,
+) -> Result<()> {
+    let usage = || {
+        Error::from(ErrorKind::CommandUsage(
+            "smtp-password <password> [site]".to_string(),
+        ))
+    };
+    let password = args.get(1).ok_or_else(usage)?;
+    let site_name = args.get(2).map(String::as_str).unwrap_or_default();
+    let site = config.site_named(site_name).ok_or_else(usage)?;
+    Preference::set_smtp_password(conn, cipher, &site.name, password)
+}
+
+/// Runs the maintenance command requested on the command line, given as the first of `args`.
+pub fn run(args: &[String], conn: &SqliteConnection, config: &Config) -> Result<()> {
+    let command = args.first().map(String::as_str).unwrap_or_default();
+    let cipher = Cipher::new(&config.privacy.secret);
+    match command {
+        "merge-threads" => {
+            //A running server keeps its cached counts until they expire after `count_cache_ttl`
+            let merged = threads::merge_duplicates(conn, &config.threads.normalise)?;
+            println!("Merged {} duplicate thread(s).", merged);
+            Ok(())
+        }
+        "export" => {
+            let subject = subject(args, command)?;
+            //Lookups by email address only find addresses which have been encrypted
+            privacy::seal_emails(conn, &cipher)?;
+            let data = privacy::export(conn, &cipher, &subject)?;
+            let json = serde_json::to_string_pretty(&data).chain_err(|| ErrorKind::DBRead)?;
+            println!("{}", json);
+            Ok(())
+        }
+        "erase" => {
+            let subject = subject(args, command)?;
+            privacy::seal_emails(conn, &cipher)?;
+            let erased = privacy::erase(conn, &cipher, &subject)?;
+            println!("Erased personal data from {} comment(s).", erased);
+            Ok(())
+        }
+        "smtp-password" => {
+            store_smtp_password(args, conn, config, &cipher)?;
+            println!("SMTP password stored.");
+            Ok(())
+        }
+        _ => Err(ErrorKind::UnknownCommand(command.to_string()).into()),
+    }
+}
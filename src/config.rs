@@ -1,28 +1,45 @@
 use serde_yaml;
 
+use crypto::util::fixed_time_eq;
+use rocket::config::Environment;
 use errors::*;
 use models::comments::gen_hash;
+use models::emails::normalise;
+use models::privacy::hmac_address;
 use std::fs::File;
 
+/// The `privacy.secret` shipped in `oration.yaml`, which is only fit for development.
+pub const DEVELOPMENT_SECRET: &str = "development-only-secret";
+
 /// The main struct which all input data from `oration.yaml` is pushed into.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
-    /// Top level location of the blog we are serving.
-    pub host: String,
-    /// Name of the blog we are serving.
-    pub blog_name: String,
-    /// A salt for slightly more anonymous `anonymous` user identification.
+    /// The default blog we are serving.
+    #[serde(flatten)]
+    pub site: Site,
+    /// Additional blogs we are serving.
+    #[serde(default)]
+    pub sites: Vec<Site>,
+    /// Origins, other than the blogs themselves, which may embed comments from this server.
+    pub allowed_origins: Vec<String>,
+    /// A salt for slightly more anonymous `anonymous` user identification, voters and stored IP addresses.
     pub salt: String,
-    /// Blog Author to highlight as an authority in comments.
-    pub author: Author,
+    /// Secret key which must be sent in the `x-admin-key` header to access administrative requests.
+    pub admin_key: String,
     /// Limit of thread nesting in comments.
     pub nesting_limit: u32,
-    /// Time limit that restricts user editing of their own comments.
-    pub edit_timeout: f32,
-    /// Email notification system and connection details.
-    pub notifications: Notifications,
-    /// Telegram notification endpoint details.
-    pub telegram: Telegram,
+    /// Reactions readers may leave on comments and threads.
+    pub reactions: Vec<String>,
+    /// Allow readers to search published comments. Administrators can always search.
+    pub public_search: bool,
+    /// Allow readers to see the previous versions of edited comments. Administrators can always see them.
+    pub public_revisions: bool,
+    /// Moderation rules applied to incoming comments.
+    pub moderation: Moderation,
+    /// Rules which apply to comment threads.
+    pub threads: Threads,
+    /// How personal data of commentors is stored.
+    pub privacy: Privacy,
 }
 
 impl Config {
@@ -34,20 +51,124 @@ impl Config {
             serde_yaml::from_reader(reader).chain_err(|| ErrorKind::Deserialize)?;
         Config::parse(&decoded_config).chain_err(|| ErrorKind::ConfigParse)?;
 
-        decoded_config.author.gen_hash();
+        decoded_config.site.author.gen_hash();
+        for site in &mut decoded_config.sites {
+            site.author.gen_hash();
+        }
 
         Ok(decoded_config)
     }
 
+    /// Every blog we are serving, starting with the default.
+    pub fn all_sites(&self) -> impl Iterator<Item = &Site> {
+        ::std::iter::once(&self.site).chain(self.sites.iter())
+    }
+
+    /// Finds the blog called `name`.
+    pub fn site_named(&self, name: &str) -> Option<&Site> {
+        self.all_sites().find(|site| site.name == name)
+    }
+
+    /// Finds the blog served from the same scheme and domain as `url`.
+    pub fn site_for_url(&self, url: &str) -> Option<&Site> {
+        let origin = origin_of(url)?;
+        self.all_sites()
+            .find(|site| origin_of(&site.host).map_or(false, |host| host == origin))
+    }
+
+    /// Checks `key` against the admin key. Administrative requests are disabled if no key is set.
+    pub fn is_admin(&self, key: &str) -> bool {
+        if self.admin_key.is_empty() || self.admin_key == "~" {
+            return false;
+        }
+        fixed_time_eq(key.as_bytes(), self.admin_key.as_bytes())
+    }
+
+    /// The form an IP address is stored in: either the address itself, or a salted HMAC of it
+    /// if `hash_ips` is enabled.
+    pub fn stored_ip(&self, ip_addr: &str) -> String {
+        if self.privacy.hash_ips {
+            hmac_address(&self.salt, ip_addr)
+        } else {
+            ip_addr.to_string()
+        }
+    }
+
     /// Additional checks to the configuration file that cannot be done implicitly
     /// by the type checker.
     fn parse(&self) -> Result<()> {
+        for (idx, site) in self.sites.iter().enumerate() {
+            let duplicate = self.sites[..idx].iter().any(|other| other.name == site.name);
+            if site.name.is_empty() || duplicate {
+                return Err(ErrorKind::SiteName(site.name.clone()).into());
+            }
+        }
+        for site in self.all_sites() {
+            site.parse(self.moderation.verify_email)?;
+        }
+        if self.privacy.secret.is_empty() || self.privacy.secret == "~" {
+            return Err(ErrorKind::EmptySecret.into());
+        }
+        if self.privacy.secret == DEVELOPMENT_SECRET
+            && Environment::active().ok() == Some(Environment::Production)
+        {
+            return Err(ErrorKind::DevelopmentSecret.into());
+        }
+
+        let verify = &self.threads.verify;
+        let verify_source = match verify.method {
+            VerifyMethod::Sitemap => &verify.sitemap,
+            VerifyMethod::Allowlist => &verify.allowlist,
+            VerifyMethod::Pattern => &verify.pattern,
+            _ => &None,
+        };
+        if (verify.method != VerifyMethod::Http && verify.method != VerifyMethod::None)
+            && verify_source.as_ref().map_or(true, |s| s.is_empty())
+        {
+            return Err(ErrorKind::EmptyVerifySource.into());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the lowercased scheme and domain of `url`, e.g. `https://example.com`.
+pub fn origin_of(url: &str) -> Option<String> {
+    let scheme_end = url.find("://")?;
+    let rest = &url[scheme_end + 3..];
+    let host_end = rest.find(|c: char| c == '/' || c == '?' || c == '#').unwrap_or_else(|| rest.len());
+    Some(format!("{}{}", &url[..scheme_end + 3], &rest[..host_end]).to_lowercase())
+}
+
+/// Details of a blog which comments are served to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Site {
+    /// Name used to select this blog in the `x-oration-site` header. The default blog has no name.
+    #[serde(default)]
+    pub name: String,
+    /// Top level location of the blog.
+    pub host: String,
+    /// Name of the blog.
+    pub blog_name: String,
+    /// Blog Author to highlight as an authority in comments.
+    pub author: Author,
+    /// Time limit that restricts user editing of their own comments.
+    pub edit_timeout: f32,
+    /// Email notification system and connection details.
+    pub notifications: Notifications,
+    /// Telegram notification endpoint details.
+    pub telegram: Telegram,
+}
+
+impl Site {
+    /// Checks the parts of a blog's configuration that cannot be done implicitly
+    /// by the type checker. Email settings are also required if `verify_email` is set.
+    fn parse(&self, verify_email: bool) -> Result<()> {
         let handle = self.host.get(0..4);
         if handle != Some("http") {
             return Err(ErrorKind::NoHTTPHandle.into());
         }
 
-        if self.notifications.new_comment {
+        if self.notifications.new_comment || self.notifications.flagged_comment || verify_email {
             // Empty values are parsed as ~, so we want to check for those
             if self
                 .notifications
@@ -88,11 +209,132 @@ impl Author {
     }
 }
 
+/// Details of the moderation system.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Moderation {
+    /// Commentors whose comments are only visible to themselves.
+    pub shadow_ban: ShadowBan,
+    /// Number of reader flags a comment can receive before it is moved into moderation.
+    /// A value of 0 never moves flagged comments.
+    pub flag_limit: u32,
+    /// Hold the first comment of each commentor in moderation until it has been approved.
+    pub first_comment: bool,
+    /// Hold comments which supply an email address until the address has been verified.
+    pub verify_email: bool,
+    /// Number of days deleted comments are kept in the trash, where they can be restored.
+    /// A value of 0 keeps no trash.
+    pub trash_days: u32,
+}
+
+/// Identities of commentors which have been shadow banned.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShadowBan {
+    /// Commentor identifier hashes.
+    pub hashes: Vec<String>,
+    /// Remote IP addresses.
+    pub ips: Vec<String>,
+    /// Email addresses, matched case insensitively.
+    pub emails: Vec<String>,
+}
+
+impl ShadowBan {
+    /// Checks if a commentor identified by `hash`, posting from `ip_addr` or leaving the email
+    /// address `email` is shadow banned.
+    pub fn matches(&self, hash: &str, ip_addr: &str, email: Option<&str>) -> bool {
+        self.hashes.iter().any(|h| h == hash)
+            || self.ips.iter().any(|ip| ip == ip_addr)
+            || email.map_or(false, |address| {
+                self.emails.iter().any(|e| normalise(e) == normalise(address))
+            })
+    }
+}
+
+/// Details of how personal data of commentors is stored.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Privacy {
+    /// Number of days IP addresses are kept alongside comments. A value of 0 keeps them forever.
+    pub ip_retention: u32,
+    /// Store a salted HMAC of IP addresses rather than the addresses themselves.
+    pub hash_ips: bool,
+    /// Server secret from which the keys encrypting email addresses and the SMTP password
+    /// are derived.
+    pub secret: String,
+}
+
+/// Details of how comment threads are handled.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Threads {
+    /// Number of days after its first comment that a thread is closed to further activity.
+    /// A value of 0 leaves threads open indefinitely.
+    pub close_after: u32,
+    /// Number of comments listed per page. A value of 0 lists every comment at once.
+    pub page_size: u32,
+    /// Number of seconds comment counts are cached for.
+    pub count_cache_ttl: u64,
+    /// Rules used to match different URIs of the same post to a single thread.
+    pub normalise: Normalise,
+    /// Read thread titles from the `<title>` of the post, rather than trusting the title
+    /// sent along with a comment.
+    pub fetch_titles: bool,
+    /// How posts are verified to exist before a thread is created for them.
+    pub verify: Verify,
+}
+
+/// Details of how posts are verified to exist on the blog.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Verify {
+    /// The verification strategy.
+    pub method: VerifyMethod,
+    /// Location of the blog's sitemap, either a url or a file path.
+    pub sitemap: Option<String>,
+    /// Location of a file listing every post path, one per line. Either a url or a file path.
+    pub allowlist: Option<String>,
+    /// Regular expression every post path must match.
+    pub pattern: Option<String>,
+    /// Number of seconds verification results are cached for.
+    pub cache_ttl: u64,
+}
+
+/// Strategies for verifying that a post exists.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMethod {
+    /// Request the post from the blog.
+    Http,
+    /// Look for the post in the blog's sitemap.
+    Sitemap,
+    /// Look for the post in a list of allowed paths.
+    Allowlist,
+    /// Match the post's path against a regular expression.
+    Pattern,
+    /// Don't verify posts at all.
+    None,
+}
+
+/// Rules applied to post paths before they are matched to a thread.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Normalise {
+    /// Removes trailing slashes, so `/post/` and `/post` are the same thread.
+    pub trailing_slash: bool,
+    /// File names which are served for a directory, e.g. `index.html`. These are removed.
+    pub index_files: Vec<String>,
+    /// Removes query strings.
+    pub query: bool,
+    /// Removes fragments.
+    pub fragment: bool,
+    /// Decodes percent encoded unreserved characters, and uppercases all other encodings.
+    pub percent_encoding: bool,
+    /// Lowercases the path, for blogs served from case insensitive file systems.
+    pub lowercase: bool,
+}
+
 /// Details of the email notification system.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Notifications {
     /// Toggle if an email is to be sent when a new comment is posted.
     pub new_comment: bool,
+    /// Toggle if an email is to be sent when a reader flags a comment.
+    pub flagged_comment: bool,
     /// SMTP connection details.
     pub smtp_server: SMTPServer,
     /// Who to send the notification to.
@@ -106,10 +348,18 @@ pub struct SMTPServer {
     pub host: String,
     /// Username for authentication.
     pub user_name: String,
-    /// Password for authentication.
+    /// Password for authentication. May be left empty if it has instead been stored
+    /// encrypted in the database with the `smtp-password` command.
     pub password: String,
 }
 
+impl SMTPServer {
+    /// Checks if the password has been given in the configuration file.
+    pub fn has_password(&self) -> bool {
+        !self.password.is_empty() && self.password != "~"
+    }
+}
+
 impl<'a> IntoIterator for &'a SMTPServer {
     type Item = &'a str;
     type IntoIter = SMTPServerIterator<'a>;
@@ -122,7 +372,7 @@ impl<'a> IntoIterator for &'a SMTPServer {
     }
 }
 
-/// Iterator helper for `SMTPServer`
+/// Iterator helper for `SMTPServer`, over the settings required in the configuration file.
 pub struct SMTPServerIterator<'a> {
     /// The SMTPServer struct.
     server: &'a SMTPServer,
@@ -136,7 +386,6 @@ impl<'a> Iterator for SMTPServerIterator<'a> {
         let result = match self.index {
             0 => &self.server.host,
             1 => &self.server.user_name,
-            2 => &self.server.password,
             _ => return None,
         };
         self.index += 1;
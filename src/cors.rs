@@ -0,0 +1,78 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::{Request, Response};
+use std::io::Cursor;
+
+use config::{origin_of, Config};
+
+/// Methods the frontend may use in cross origin requests.
+const ALLOW_METHODS: &str = "GET, POST, DELETE";
+/// Headers the frontend may send in cross origin requests.
+const ALLOW_HEADERS: &str = "Content-Type, x-auth-hash, x-oration-site";
+/// Number of seconds browsers may cache the result of a preflight request.
+const MAX_AGE: &str = "86400";
+
+/// Allows blogs on other domains to embed comments from this server, by answering
+/// preflight requests and marking responses as readable from the allowed origins.
+pub struct Cors {
+    /// Scheme and domain of every origin which may make requests.
+    origins: Vec<String>,
+}
+
+impl Cors {
+    /// Builds the list of allowed origins from the hosts of every blog we are serving,
+    /// along with any additional `allowed_origins` in the configuration file.
+    pub fn new(config: &Config) -> Cors {
+        let origins = config
+            .all_sites()
+            .map(|site| site.host.as_str())
+            .chain(config.allowed_origins.iter().map(|origin| origin.as_str()))
+            .filter_map(origin_of)
+            .collect();
+        Cors { origins }
+    }
+
+    /// Checks if requests from `origin` are allowed.
+    fn allows(&self, origin: &str) -> bool {
+        origin_of(origin).map_or(false, |origin| self.origins.contains(&origin))
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "Cross origin requests",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if !request.uri().path().starts_with("/oration") {
+            return;
+        }
+        //Responses differ depending on where the request came from, so caches must keep them apart
+        response.adjoin_raw_header("Vary", "Origin");
+
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) if self.allows(origin) => origin.to_string(),
+            _ => return,
+        };
+        response.set_raw_header("Access-Control-Allow-Origin", origin);
+
+        let preflight = request.method() == Method::Options
+            && request
+                .headers()
+                .get_one("Access-Control-Request-Method")
+                .is_some();
+        if preflight {
+            //No routes handle OPTIONS, so Rocket has answered with a 404 we replace here
+            response.adjoin_raw_header("Vary", "Access-Control-Request-Method");
+            response.adjoin_raw_header("Vary", "Access-Control-Request-Headers");
+            response.set_raw_header("Access-Control-Allow-Methods", ALLOW_METHODS);
+            response.set_raw_header("Access-Control-Allow-Headers", ALLOW_HEADERS);
+            response.set_raw_header("Access-Control-Max-Age", MAX_AGE);
+            response.set_status(Status::NoContent);
+            response.set_sized_body(Cursor::new(""));
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of cached counts above which expired entries are pruned.
+const CACHE_PRUNE: usize = 4096;
+
+/// Remembers comment counts for a short while, so blog index pages which are loaded often
+/// don't need to query the database every time.
+pub struct CountCache {
+    /// How long counts are trusted for.
+    ttl: Duration,
+    /// Previous counts for each site and post url, and when they were obtained.
+    counts: Mutex<HashMap<(String, String), (i64, Instant)>>,
+}
+
+impl CountCache {
+    /// Creates an empty cache which trusts counts for `ttl` seconds.
+    pub fn new(ttl: u64) -> CountCache {
+        CountCache {
+            ttl: Duration::from_secs(ttl),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached count of the post `url` on the site `site_name`, if it hasn't expired.
+    pub fn get(&self, site_name: &str, url: &str) -> Option<i64> {
+        let counts = self.counts.lock().ok()?;
+        counts
+            .get(&(site_name.to_string(), url.to_string()))
+            .and_then(|&(count, cached)| {
+                if cached.elapsed() < self.ttl {
+                    Some(count)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Stores the count of the post `url` on the site `site_name`.
+    pub fn insert(&self, site_name: &str, url: &str, count: i64) {
+        if let Ok(mut counts) = self.counts.lock() {
+            if counts.len() > CACHE_PRUNE {
+                let ttl = self.ttl;
+                counts.retain(|_, &mut (_, cached)| cached.elapsed() < ttl);
+            }
+            counts.insert(
+                (site_name.to_string(), url.to_string()),
+                (count, Instant::now()),
+            );
+        }
+    }
+
+    /// Forgets every cached count, so changes are visible immediately.
+    pub fn clear(&self) {
+        if let Ok(mut counts) = self.counts.lock() {
+            counts.clear();
+        }
+    }
+}
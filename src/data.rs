@@ -1,9 +1,13 @@
-use rocket::http::Status;
-use rocket::request::{self, FromRequest, Request};
-use rocket::Outcome;
+use rocket::http::{RawStr, Status};
+use rocket::request::{self, FromFormValue, FromRequest, Request};
+use rocket::{Outcome, State};
+use std::fmt;
+use std::ops::Deref;
+
+use config::{Config, Site};
 
 //NOTE: we can use FormInput<'c>, url: &'c RawStr, for unvalidated data if/when we need it.
-#[derive(Debug, FromForm)]
+#[derive(FromForm)]
 /// Incoming data from the web based form for a new comment.
 pub struct FormInput {
     /// Comment from textarea.
@@ -20,6 +24,24 @@ pub struct FormInput {
     pub title: String,
     /// Path of post.
     pub path: String,
+    /// Token kept by the commentor's browser, which vouches for their verified email address.
+    pub browser: Option<String>,
+}
+
+/// Written by hand so the browser token is left out of the debug information sent along with
+/// notifications.
+impl fmt::Debug for FormInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FormInput")
+            .field("comment", &self.comment)
+            .field("parent", &self.parent)
+            .field("name", &self.name)
+            .field("email", &self.email)
+            .field("url", &self.url)
+            .field("title", &self.title)
+            .field("path", &self.path)
+            .finish()
+    }
 }
 
 impl FormInput {
@@ -51,6 +73,65 @@ pub struct FormEdit {
     pub url: Option<String>,
 }
 
+#[derive(Debug, FromForm)]
+/// Incoming data from a reader reporting a comment.
+pub struct FormFlag {
+    /// Why the comment is being reported.
+    pub reason: String,
+}
+
+/// Order in which comments are listed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    /// Earliest comments first.
+    Oldest,
+    /// Latest comments first.
+    Newest,
+    /// Comments with the most votes first.
+    Top,
+    /// Comments with the highest Wilson score first.
+    Best,
+    /// Comments with many, evenly split, votes first.
+    Controversial,
+    /// Comments with the most votes for their age first.
+    Hot,
+}
+
+impl<'v> FromFormValue<'v> for SortOrder {
+    type Error = &'v RawStr;
+
+    fn from_form_value(value: &'v RawStr) -> Result<SortOrder, &'v RawStr> {
+        match value.as_str() {
+            "oldest" => Ok(SortOrder::Oldest),
+            "newest" => Ok(SortOrder::Newest),
+            "top" => Ok(SortOrder::Top),
+            "best" => Ok(SortOrder::Best),
+            "controversial" => Ok(SortOrder::Controversial),
+            "hot" => Ok(SortOrder::Hot),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Details of a flagged comment, used to notify the admin.
+#[derive(Debug)]
+pub struct FlagReport {
+    /// Id of the flagged comment.
+    pub id: i32,
+    /// Text of the flagged comment.
+    pub text: String,
+    /// Path of the post the comment is on.
+    pub path: String,
+    /// Title of the post the comment is on.
+    pub title: String,
+    /// Reason given by the most recent reporter.
+    pub reason: String,
+    /// Total number of times the comment has been flagged.
+    pub count: i64,
+    /// True if this report moved the comment into moderation.
+    pub moderated: bool,
+}
+
 /// Hash of the user which wants to edit/delete a comment.
 #[derive(PartialEq)]
 pub struct AuthHash(String);
@@ -75,4 +156,75 @@ impl AuthHash {
         let &AuthHash(ref hash) = self;
         hash == compare
     }
+
+    /// Returns the hash as a string slice.
+    pub fn as_str(&self) -> &str {
+        let &AuthHash(ref hash) = self;
+        hash
+    }
+}
+
+/// Request guard for administrative requests. Only succeeds if the `x-admin-key` header
+/// matches the admin key set in the configuration file.
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        let config = match request.guard::<State<Config>>() {
+            Outcome::Success(config) => config,
+            Outcome::Failure(e) => return Outcome::Failure(e),
+            Outcome::Forward(_) => return Outcome::Forward(()),
+        };
+
+        let keys: Vec<_> = request.headers().get("x-admin-key").collect();
+        if keys.len() == 1 && config.is_admin(keys[0]) {
+            Outcome::Success(Admin)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Request guard for the blog a request was made from. The blog is selected by name in the
+/// `x-oration-site` header, or matched against the `Origin` or `Referer` headers. If none of
+/// these are given, the default blog is used.
+pub struct CurrentSite<'r>(&'r Site);
+
+impl<'a, 'r> FromRequest<'a, 'r> for CurrentSite<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<CurrentSite<'r>, ()> {
+        let config = match request.guard::<State<Config>>() {
+            Outcome::Success(config) => config.inner(),
+            Outcome::Failure(e) => return Outcome::Failure(e),
+            Outcome::Forward(_) => return Outcome::Forward(()),
+        };
+
+        let headers = request.headers();
+        if let Some(name) = headers.get_one("x-oration-site") {
+            return match config.site_named(name) {
+                Some(site) => Outcome::Success(CurrentSite(site)),
+                None => Outcome::Failure((Status::NotFound, ())),
+            };
+        }
+        let site = headers
+            .get_one("Origin")
+            .and_then(|origin| config.site_for_url(origin))
+            .or_else(|| {
+                headers
+                    .get_one("Referer")
+                    .and_then(|referer| config.site_for_url(referer))
+            }).unwrap_or(&config.site);
+        Outcome::Success(CurrentSite(site))
+    }
+}
+
+impl<'r> Deref for CurrentSite<'r> {
+    type Target = Site;
+
+    fn deref(&self) -> &Site {
+        self.0
+    }
 }
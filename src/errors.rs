@@ -12,6 +12,10 @@ error_chain!{
                 description("Cannot read thread info")
                 display("Unable to read thread information for {} from database", uri)
         }
+        UriTaken(uri: String) {
+                description("URI already in use")
+                display("The URI {} already belongs to another thread", uri)
+        }
         DBRead {
                 description("Cannot parse db response")
                 display("Unable to parse response from database query")
@@ -32,6 +36,14 @@ error_chain!{
                 description("Config file not found")
                 display("Unable to read configuration file oration.yaml")
         }
+        UnknownCommand(command: String) {
+                description("Unknown command")
+                display("Unknown command '{}'. Available commands: merge-threads, export, erase, smtp-password", command)
+        }
+        CommandUsage(usage: String) {
+                description("Missing command argument")
+                display("Usage: oration {}", usage)
+        }
         ConfigParse {
             description("Error parsing config")
             display("an error occurred trying to parse the configuratation file")
@@ -48,6 +60,22 @@ error_chain!{
                 description("No HTTP handle")
                 display("The configuration parameter 'host' requires either a http:// or https:// prefix")
         }
+        SiteName(name: String) {
+                description("Invalid site name")
+                display("Every entry in 'sites' requires a unique, non-empty name, found '{}'", name)
+        }
+        EmptyVerifySource {
+                description("Invalid verify configuration")
+                display("The chosen post verification method requires its sitemap, allowlist or pattern to be set")
+        }
+        InvalidVerifyPattern {
+                description("Invalid verify pattern")
+                display("The post verification pattern is not a valid regular expression")
+        }
+        VerifySource {
+                description("Cannot read verify source")
+                display("Unable to read the sitemap or allowlist used to verify posts")
+        }
         EmptySMTP {
                 description("Invalid SMTP configuration")
                 display("Email notifications have been enabled, but one or more of the SMTP server configuration options are empty")
@@ -80,9 +108,41 @@ error_chain!{
                 description("Failed SMTP handshake")
                 display("Could not attach to SMTP server")
         }
-        AlreadyVoted {
-                description("Cannot Re-Vote")
-                display("User has already voted on this comment")
+        NoComment(id: i32) {
+                description("Cannot find comment")
+                display("Unable to find a comment with id {} that can be acted on in database", id)
+        }
+        InvalidToken {
+                description("Invalid verification token")
+                display("Verification token does not match any email address awaiting verification")
+        }
+        AlreadyFlagged {
+                description("Cannot Re-Flag")
+                display("User has already flagged this comment")
+        }
+        NotInTrash(id: i32) {
+                description("Cannot find trashed comment")
+                display("Unable to find entry {} in the trash", id)
+        }
+        NoRevision(id: i32) {
+                description("Cannot find revision")
+                display("Unable to find revision {} of the requested comment", id)
+        }
+        Decrypt {
+                description("Cannot decrypt data")
+                display("Unable to decrypt data from the database, has the privacy secret changed?")
+        }
+        EmptySecret {
+                description("No privacy secret")
+                display("The configuration parameter 'privacy.secret' is required to encrypt personal data. Set it in oration.yaml to a long random string, e.g. the output of `openssl rand -base64 32`")
+        }
+        DevelopmentSecret {
+                description("Development privacy secret")
+                display("The development 'privacy.secret' shipped in oration.yaml can't be used in production. Replace it with a long random string, e.g. the output of `openssl rand -base64 32`")
+        }
+        NoSmtpPassword(site: String) {
+                description("No SMTP password")
+                display("No SMTP password has been set for the site '{}', either in oration.yaml or with the smtp-password command", site)
         }
     }
 }
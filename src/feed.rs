@@ -0,0 +1,61 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use models::comments::CommentOnPost;
+use render::escape;
+
+/// Formats a timestamp from the database as required by Atom.
+fn timestamp(time: NaiveDateTime) -> String {
+    DateTime::<Utc>::from_utc(time, Utc).to_rfc3339()
+}
+
+/// Builds an Atom feed named `title` of the given comments, which should be newest first.
+/// `link` is the page the feed describes and doubles as the feed's id, whilst `host` is the
+/// blog the comments were left on. Each entry uses the permalink of its comment as an id.
+pub fn atom(title: &str, link: &str, host: &str, comments: &[CommentOnPost]) -> String {
+    let updated = comments
+        .iter()
+        .map(|c| c.modified.unwrap_or(c.created))
+        .max()
+        .map_or_else(|| Utc::now().to_rfc3339(), timestamp);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <id>{}</id>\n", escape(link)));
+    xml.push_str(&format!("  <title>{}</title>\n", escape(title)));
+    xml.push_str(&format!("  <link href=\"{}\"/>\n", escape(link)));
+    xml.push_str(&format!("  <updated>{}</updated>\n", updated));
+    xml.push_str("  <generator>oration</generator>\n");
+
+    for comment in comments {
+        let permalink = escape(&comment.permalink(host));
+        let author = comment.author().unwrap_or_else(|| "Anonymous".to_string());
+        let post = comment.title.as_ref().unwrap_or(&comment.uri);
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <id>{}</id>\n", permalink));
+        xml.push_str(&format!(
+            "    <title>{} on {}</title>\n",
+            escape(&author),
+            escape(post)
+        ));
+        xml.push_str(&format!("    <link href=\"{}\"/>\n", permalink));
+        xml.push_str(&format!(
+            "    <author><name>{}</name></author>\n",
+            escape(&author)
+        ));
+        xml.push_str(&format!(
+            "    <published>{}</published>\n",
+            timestamp(comment.created)
+        ));
+        xml.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            timestamp(comment.modified.unwrap_or(comment.created))
+        ));
+        xml.push_str(&format!(
+            "    <content type=\"text\">{}</content>\n",
+            escape(&comment.text)
+        ));
+        xml.push_str("  </entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
@@ -14,7 +14,6 @@
 // `error_chain!` can recurse deeply
 #![recursion_limit = "1024"]
 
-extern crate bincode;
 extern crate chrono;
 #[macro_use]
 extern crate diesel;
@@ -31,7 +30,6 @@ extern crate rocket_contrib;
 extern crate serde_derive;
 extern crate yansi;
 //extern crate argon2rs;
-extern crate bloomfilter;
 extern crate crypto;
 extern crate itertools;
 #[macro_use]
@@ -43,44 +41,78 @@ extern crate log;
 extern crate openssl_probe;
 extern crate regex;
 extern crate reqwest;
+extern crate serde_json;
 extern crate serde_yaml;
 
+/// Encrypts personal data before it is stored.
+mod cipher;
+/// Maintenance commands which can be run from the command line.
+mod commands;
 /// Loads configuration data from disk.
 mod config;
+/// Handles cross origin requests from embedded comments.
+mod cors;
+/// Caches comment counts.
+mod counts;
 /// Houses Data Structures that are needed in multiple modules.
 mod data;
 /// Handles the database connection pool.
 mod db;
 /// Handles the error chain of the program.
 mod errors;
+/// Builds Atom feeds of comments.
+mod feed;
 /// SQL <----> Rust inerop using Diesel.
 mod models;
 /// Sends notifications to admin.
 mod notify;
+/// Renders comment text for use outside of the frontend.
+mod render;
+/// Removes personal data once it is no longer needed.
+mod retention;
 /// Verbose schema for the comment database.
 mod schema;
 /// Serves up static files through Rocket.
 mod static_files;
+/// Verifies posts exist before threads are created for them.
+mod verify;
 /// Tests for the Rocket side of the app.
 #[cfg(test)]
 mod tests;
 
-use config::Config;
+use cipher::Cipher;
+use config::{Config, Site, DEVELOPMENT_SECRET};
+use cors::Cors;
+use counts::CountCache;
 use crypto::digest::Digest;
 use crypto::sha2::Sha224;
-use data::{AuthHash, FormEdit, FormInput};
+use data::{Admin, AuthHash, CurrentSite, FormEdit, FormFlag, FormInput, SortOrder};
 use errors::Error;
-use models::comments::{self, Comment, CommentEdits, InsertedComment, NestedComment};
+use models::audit::{self, AuditEntry};
+use models::comments::{
+    self, Comment, CommentContext, CommentEdits, CommentOnPost, CommentPage, InsertedComment,
+    Listing, NestedComment, RecentComment,
+};
+use models::reactions::{self, Tally};
+use models::revisions::{self, Revision};
+use models::search::{self, SearchResult};
+use models::trash::{self, TrashedComment};
+use models::{emails, flags, votes};
 use models::preferences::Preference;
+use models::privacy::{self, PersonalData, Subject};
 use models::threads;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::request::Form;
-use rocket::response::{status, Failure, NamedFile};
+use rocket::response::{content, status, Failure, NamedFile, Redirect};
+use rocket::fairing::AdHoc;
 use rocket::State;
 use rocket_contrib::Json;
+use std::collections::BTreeMap;
+use std::env;
 use std::io;
 use std::net::SocketAddr;
 use std::process;
+use verify::Verifier;
 use yansi::Paint;
 
 /// Serve up the index file. This is only useful for development. Should not be used in a release.
@@ -90,39 +122,201 @@ fn index() -> io::Result<NamedFile> {
     NamedFile::open("public/index.html")
 }
 
+#[derive(Serialize, Debug)]
+/// Explanation sent to the frontend when a request is refused.
+struct Refusal {
+    /// Why the request was refused.
+    reason: String,
+}
+
+/// Refuses a request with the status `code`, explaining the `reason` to the frontend.
+fn refuse(code: Status, reason: &str) -> status::Custom<Json<Refusal>> {
+    status::Custom(
+        code,
+        Json(Refusal {
+            reason: reason.to_string(),
+        }),
+    )
+}
+
+/// Refuses a request about the comment `id` unless it was left on `site`. The site a request
+/// is made from is chosen by the client, so its settings (the edit timeout and notifications,
+/// for example) may only be applied to comments which were left on it.
+fn check_site(
+    conn: &db::Conn,
+    site: &Site,
+    id: i32,
+) -> Result<(), status::Custom<Json<Refusal>>> {
+    match Comment::site(conn, id) {
+        Ok(ref name) if *name == site.name => Ok(()),
+        Ok(_) | Err(errors::Error(errors::ErrorKind::NoComment(_), _)) => Err(refuse(
+            Status::NotFound,
+            "Unable to find the requested comment.",
+        )),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to read comment information.",
+            ))
+        }
+    }
+}
+
+/// Refuses a request if the comment `id` was not left on `site`, or belongs to a thread that
+/// has been closed.
+fn check_open(
+    conn: &db::Conn,
+    site: &Site,
+    id: i32,
+    close_after: u32,
+) -> Result<(), status::Custom<Json<Refusal>>> {
+    check_site(conn, site, id)?;
+    match Comment::thread(conn, id) {
+        Ok(tid) => check_thread_open(conn, tid, close_after),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(Status::NotFound, "Unable to find the requested comment."))
+        }
+    }
+}
+
+/// Refuses a request if the thread `tid` has been closed.
+fn check_thread_open(
+    conn: &db::Conn,
+    tid: i32,
+    close_after: u32,
+) -> Result<(), status::Custom<Json<Refusal>>> {
+    match threads::is_closed(conn, tid, close_after) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(refuse(Status::Forbidden, "This thread has been closed.")),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to read thread information.",
+            ))
+        }
+    }
+}
+
 /// Process comment input from form.
 #[post("/oration", data = "<comment>")]
 fn new_comment(
     conn: db::Conn,
     comment: Result<Form<FormInput>, Option<String>>,
     config: State<Config>,
+    site: CurrentSite,
+    verifier: State<Verifier>,
+    cache: State<CountCache>,
+    cipher: State<Cipher>,
     remote_addr: SocketAddr,
-) -> Result<Json<InsertedComment>, Failure> {
+) -> Result<Json<InsertedComment>, status::Custom<Json<Refusal>>> {
     match comment {
         Ok(f) => {
             //If the comment form data is valid, proceed to comment insertion
             let form = f.into_inner();
             let ip_addr = remote_addr.ip().to_string();
             //Get thread id from the db, create if needed
-            match threads::gen_or_get_id(&conn, &config.host, &form.title, &form.path) {
+            match threads::gen_or_get_id(
+                &conn,
+                &site,
+                &form.title,
+                &form.path,
+                &config.threads,
+                &verifier,
+            ) {
                 Ok(tid) => {
-                    match Comment::insert(&conn, tid, &form, &ip_addr, config.nesting_limit) {
+                    match threads::is_closed(&conn, tid, config.threads.close_after) {
+                        Ok(false) => {}
+                        Ok(true) => {
+                            return Err(refuse(
+                                Status::Forbidden,
+                                "This thread has been closed to new comments.",
+                            ));
+                        }
+                        Err(err) => {
+                            print_errors(&err);
+                            return Err(refuse(
+                                Status::InternalServerError,
+                                "Unable to read thread information.",
+                            ));
+                        }
+                    }
+                    match Comment::insert(
+                        &conn,
+                        tid,
+                        &cipher,
+                        &form,
+                        &ip_addr,
+                        &config.stored_ip(&ip_addr),
+                        config.nesting_limit,
+                        &config.moderation,
+                    ) {
                         Err(err) => {
                             //Something went wrong, return a 500
                             print_errors(&err);
-                            Err(Failure(Status::InternalServerError))
+                            Err(refuse(
+                                Status::InternalServerError,
+                                "Unable to save the comment.",
+                            ))
                         }
-                        Ok(comment) => {
+                        Ok(mut comment) => {
                             //All good, return the comment
-                            //Send notification to admin
-                            if config.notifications.new_comment {
-                                match notify::send_notification(
-                                    &form,
-                                    &config.notifications,
-                                    &config.host,
-                                    &config.blog_name,
-                                    &ip_addr,
+                            cache.clear();
+                            if comment.is_live() {
+                                //The post may have been retitled since the thread was created
+                                if let Err(err) = threads::update_title(
+                                    &conn,
+                                    &site,
+                                    tid,
+                                    &form.title,
+                                    &form.path,
+                                    &config.threads,
                                 ) {
+                                    print_errors(&err);
+                                }
+                            }
+                            if comment.is_unverified() {
+                                //Ask the commentor to verify their email address
+                                let browser = form.browser.as_ref().map(String::as_str);
+                                let sent = comment.keep_browser(&conn, browser).and_then(|token| {
+                                    send_verification(&conn, &cipher, &form, &site, &token)
+                                });
+                                match sent {
+                                    Ok(_) => {
+                                        log::info!(
+                                            "📧  {}",
+                                            Paint::blue("Email verification request sent.")
+                                        )
+                                    }
+                                    Err(err) => {
+                                        print_errors(&err);
+                                        //The commentor can't verify their address, so hold the
+                                        //comment for the admin to approve instead
+                                        if let Err(err) = comment.await_approval(&conn) {
+                                            print_errors(&err);
+                                        }
+                                    }
+                                }
+                            }
+                            //Send notification to admin, unless the commentor is shadow banned
+                            //or the comment may never be verified
+                            let notify = !comment.is_shadow_banned() && !comment.is_unverified();
+                            if notify && site.notifications.new_comment {
+                                let sent = smtp_password(&conn, &cipher, &site)
+                                    .and_then(|password| {
+                                        notify::send_notification(
+                                            &form,
+                                            &site.notifications,
+                                            &site.host,
+                                            &site.blog_name,
+                                            &ip_addr,
+                                            comment.is_pending(),
+                                            &password,
+                                        )
+                                    });
+                                match sent {
                                     Ok(_) => log::info!(
                                         "📧  {}",
                                         Paint::blue("New comment email notification sent.")
@@ -132,12 +326,13 @@ fn new_comment(
                                     }
                                 }
                             }
-                            if config.telegram.push_notifications {
+                            if notify && site.telegram.push_notifications {
                                 match notify::push_telegram(
                                     &form,
-                                    &config.telegram,
-                                    &config.host,
+                                    &site.telegram,
+                                    &site.host,
                                     &ip_addr,
+                                    comment.is_pending(),
                                 ) {
                                     Ok(_) => log::info!(
                                         "📧  {}",
@@ -161,16 +356,89 @@ fn new_comment(
                         errors::Error(errors::ErrorKind::PathCheckFailed, _) => {
                             //The requsted path doesn't exist on the server
                             //Most likely an attempt at injecting junk into the db through the post method
-                            Err(Failure(Status::Forbidden))
+                            Err(refuse(
+                                Status::Forbidden,
+                                "This post does not exist on the blog.",
+                            ))
                         }
-                        _ => Err(Failure(Status::InternalServerError)),
+                        _ => Err(refuse(
+                            Status::InternalServerError,
+                            "Unable to read thread information.",
+                        )),
                     }
                 }
             }
         }
         Err(_) => {
             //The form request was malformed, 400
-            Err(Failure(Status::BadRequest))
+            Err(refuse(Status::BadRequest, "The comment form was malformed."))
+        }
+    }
+}
+
+/// Returns the password of the SMTP server of `site`: from the configuration file if given
+/// there, otherwise decrypted from the database. Only called as a notification is sent.
+fn smtp_password(conn: &db::Conn, cipher: &Cipher, site: &Site) -> errors::Result<String> {
+    let server = &site.notifications.smtp_server;
+    if server.has_password() {
+        return Ok(server.password.to_owned());
+    }
+    Preference::get_smtp_password(conn, cipher, &site.name)?
+        .ok_or_else(|| errors::ErrorKind::NoSmtpPassword(site.name.clone()).into())
+}
+
+/// Generates a verification token for the email address supplied in `form`, and sends it
+/// to the commentor as a link. The verification vouches for the commentor's browser, which
+/// keeps the token `browser`.
+fn send_verification(
+    conn: &db::Conn,
+    cipher: &Cipher,
+    form: &FormInput,
+    site: &Site,
+    browser: &str,
+) -> errors::Result<()> {
+    let address = form.email.to_owned().unwrap_or_default();
+    let token = emails::request_verification(conn, cipher, &address, browser)?;
+    let link = format!(
+        "{}/oration/verify?token={}",
+        site.host.trim_right_matches('/'),
+        token
+    );
+    notify::send_verification(
+        form,
+        &link,
+        &site.notifications,
+        &site.host,
+        &site.blog_name,
+        &smtp_password(conn, cipher, site)?,
+    )
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/verify?`.
+struct Verification {
+    /// The token sent to the commentor.
+    token: String,
+}
+
+/// Verifies a commentor's email address from the link sent to them, publishing
+/// any comments which were awaiting verification.
+#[get("/oration/verify?<verification>")]
+fn verify_email(
+    conn: db::Conn,
+    cache: State<CountCache>,
+    verification: Verification,
+) -> Result<String, Failure> {
+    match emails::verify(&conn, &verification.token) {
+        Ok(_) => {
+            cache.clear();
+            Ok(String::from(
+                "Thank you, your email address has been verified and your comments are now published.",
+            ))
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
         }
     }
 }
@@ -188,7 +456,7 @@ struct Initialise {
 
 /// Gets a Sha224 hash from a clients IP along with the blog's author hash.
 #[get("/oration/init")]
-fn initialise(remote_addr: SocketAddr, config: State<Config>) -> Json<Initialise> {
+fn initialise(remote_addr: SocketAddr, site: CurrentSite) -> Json<Initialise> {
     let ip_addr = remote_addr.ip().to_string();
     // create a Sha224 object
     let mut hasher = Sha224::new();
@@ -197,8 +465,8 @@ fn initialise(remote_addr: SocketAddr, config: State<Config>) -> Json<Initialise
 
     let to_send = Initialise {
         user_ip: hasher.result_str(),
-        blog_author: config.author.hash.to_owned(),
-        edit_timeout: config.edit_timeout,
+        blog_author: site.author.hash.to_owned(),
+        edit_timeout: site.edit_timeout,
     };
 
     Json(to_send)
@@ -220,16 +488,25 @@ struct CommentId {
 fn delete_comment(
     conn: db::Conn,
     config: State<Config>,
+    site: CurrentSite,
+    cache: State<CountCache>,
     identifier: CommentId,
     hash: AuthHash,
 ) -> Result<String, Failure> {
-    if let Err(err) = comments::update_authorised(&conn, &hash, identifier.id, config.edit_timeout)
+    if let Err(refusal) = check_site(&conn, &site, identifier.id) {
+        return Err(Failure(refusal.0));
+    }
+    if let Err(err) = comments::update_authorised(&conn, &hash, identifier.id, site.edit_timeout)
     {
         print_errors(&err);
         return Err(Failure(Status::Unauthorized));
     };
-    match Comment::delete(&conn, identifier.id) {
-        Ok(_) => Ok(identifier.id.to_string()),
+    let keep_days = config.moderation.trash_days;
+    match Comment::delete(&conn, identifier.id, hash.as_str(), keep_days) {
+        Ok(_) => {
+            cache.clear();
+            Ok(identifier.id.to_string())
+        }
         Err(err) => {
             print_errors(&err);
             Err(Failure(Status::NotFound))
@@ -243,67 +520,759 @@ fn delete_comment(
 fn edit_comment(
     conn: db::Conn,
     config: State<Config>,
+    cipher: State<Cipher>,
+    site: CurrentSite,
     identifier: CommentId,
     hash: AuthHash,
     edits: Result<Form<FormEdit>, Option<String>>,
     remote_addr: SocketAddr,
-) -> Result<Json<CommentEdits>, Failure> {
-    if let Err(err) = comments::update_authorised(&conn, &hash, identifier.id, config.edit_timeout)
+) -> Result<Json<CommentEdits>, status::Custom<Json<Refusal>>> {
+    check_open(&conn, &site, identifier.id, config.threads.close_after)?;
+    if let Err(err) = comments::update_authorised(&conn, &hash, identifier.id, site.edit_timeout)
     {
         print_errors(&err);
-        return Err(Failure(Status::Unauthorized));
+        return Err(refuse(
+            Status::Unauthorized,
+            "You are not able to edit this comment.",
+        ));
     };
     match edits {
         Ok(f) => {
             //If the comment form data is valid, proceed to updating the comment
             let form = f.into_inner();
             let ip_addr = remote_addr.ip().to_string();
-            match Comment::update(&conn, identifier.id, &form, &ip_addr) {
+            match Comment::update(&conn, &cipher, identifier.id, &form, &ip_addr) {
                 Ok(edits) => Ok(Json(edits)),
                 Err(err) => {
                     print_errors(&err);
-                    Err(Failure(Status::NotFound))
+                    Err(refuse(Status::NotFound, "Unable to update the comment."))
                 }
             }
         }
         Err(_) => {
             //The form request was malformed or not UTF8 encoded: 400
-            Err(Failure(Status::BadRequest))
+            Err(refuse(Status::BadRequest, "The edit form was malformed."))
         }
     }
 }
 
-/// Likes a comment so long as the current user has not done so already.
+/// Lists the previous versions of a published comment, oldest first. Only available if
+/// `public_revisions` is enabled.
+#[get("/oration/revisions?<identifier>")]
+fn get_revisions(
+    conn: db::Conn,
+    config: State<Config>,
+    identifier: CommentId,
+) -> Result<Json<Vec<Revision>>, status::Custom<Json<Refusal>>> {
+    if !config.public_revisions {
+        return Err(refuse(Status::Forbidden, "Comment revisions are not public."));
+    }
+    let listed = Comment::is_live(&conn, identifier.id).and_then(|live| {
+        if live {
+            revisions::list(&conn, identifier.id).map(Some)
+        } else {
+            Ok(None)
+        }
+    });
+    match listed {
+        Ok(Some(list)) => Ok(Json(list)),
+        Ok(None) => Err(refuse(Status::NotFound, "No such comment.")),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to list revisions.",
+            ))
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// The state of a reader's vote on a comment, sent back once they have voted.
+struct VoteState {
+    /// The id of the comment.
+    id: i32,
+    /// True for a like, false for a dislike, or nothing if the vote was retracted.
+    vote: Option<bool>,
+}
+
+/// Likes a comment. If the current user has already liked it, their like is retracted instead.
 #[post("/oration/like?<identifier>")]
 fn like_comment(
     conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    identifier: CommentId,
+    remote_addr: SocketAddr,
+) -> Result<Json<VoteState>, status::Custom<Json<Refusal>>> {
+    cast_vote(&conn, &config, &site, identifier.id, &remote_addr, true)
+}
+
+/// Dislikes a comment. If the current user has already disliked it, their dislike is retracted instead.
+#[post("/oration/dislike?<identifier>")]
+fn dislike_comment(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
     identifier: CommentId,
     remote_addr: SocketAddr,
-) -> Result<String, status::Custom<String>> {
+) -> Result<Json<VoteState>, status::Custom<Json<Refusal>>> {
+    cast_vote(&conn, &config, &site, identifier.id, &remote_addr, false)
+}
+
+/// Records a like or dislike on the comment `id`, so long as it is live, was left on `site`
+/// and its thread is open. Returns the state of the reader's vote afterwards.
+fn cast_vote(
+    conn: &db::Conn,
+    config: &Config,
+    site: &Site,
+    id: i32,
+    remote_addr: &SocketAddr,
+    upvote: bool,
+) -> Result<Json<VoteState>, status::Custom<Json<Refusal>>> {
+    check_open(conn, site, id, config.threads.close_after)?;
     let ip_addr = remote_addr.ip().to_string();
-    match Comment::vote(&conn, identifier.id, &ip_addr, true) {
-        Ok(_) => Ok(identifier.id.to_string()),
+    match Comment::vote(conn, id, &ip_addr, &config.salt, upvote) {
+        Ok(vote) => Ok(Json(VoteState { id, vote })),
+        Err(errors::Error(errors::ErrorKind::NoComment(_), _)) => Err(refuse(
+            Status::NotFound,
+            "Unable to find the requested comment.",
+        )),
         Err(err) => {
             print_errors(&err);
-            //TODO: A custom status with a Failure that doesn't require &'static strings
-            Err(status::Custom(Status::Forbidden, identifier.id.to_string()))
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to record your vote.",
+            ))
         }
     }
 }
 
-/// Dislikes a comment so long as the current user has not done so already.
-#[post("/oration/dislike?<identifier>")]
-fn dislike_comment(
+/// Refuses reactions which are not listed in the configuration file.
+fn check_reaction(config: &Config, reaction: &str) -> Result<(), status::Custom<Json<Refusal>>> {
+    if config.reactions.iter().any(|r| r == reaction) {
+        Ok(())
+    } else {
+        Err(refuse(Status::BadRequest, "This reaction is not available."))
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/react?`.
+struct CommentReaction {
+    /// The id of the comment.
+    id: i32,
+    /// The reaction to leave or remove.
+    reaction: String,
+}
+
+/// Leaves a reaction on a comment, returning the updated reactions of the comment.
+#[post("/oration/react?<target>")]
+fn react_comment(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    target: CommentReaction,
+    remote_addr: SocketAddr,
+) -> Result<Json<Tally>, status::Custom<Json<Refusal>>> {
+    check_reaction(&config, &target.reaction)?;
+    check_open(&conn, &site, target.id, config.threads.close_after)?;
+    let voter = votes::voter_identity(&remote_addr.ip().to_string(), &config.salt);
+    match reactions::add_to_comment(&conn, target.id, &voter, &target.reaction) {
+        Ok(tally) => Ok(Json(tally)),
+        Err(errors::Error(errors::ErrorKind::NoComment(_), _)) => Err(refuse(
+            Status::NotFound,
+            "Unable to find the requested comment.",
+        )),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to record your reaction.",
+            ))
+        }
+    }
+}
+
+/// Removes a reaction the current user left on a comment, returning the updated reactions
+/// of the comment.
+#[delete("/oration/react?<target>")]
+fn unreact_comment(
     conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    target: CommentReaction,
+    remote_addr: SocketAddr,
+) -> Result<Json<Tally>, status::Custom<Json<Refusal>>> {
+    check_open(&conn, &site, target.id, config.threads.close_after)?;
+    let voter = votes::voter_identity(&remote_addr.ip().to_string(), &config.salt);
+    match reactions::remove_from_comment(&conn, target.id, &voter, &target.reaction) {
+        Ok(tally) => Ok(Json(tally)),
+        Err(errors::Error(errors::ErrorKind::NoComment(_), _)) => Err(refuse(
+            Status::NotFound,
+            "Unable to find the requested comment.",
+        )),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to remove your reaction.",
+            ))
+        }
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/thread/react?`.
+struct ThreadReaction {
+    /// The url of the post.
+    url: String,
+    /// The reaction to leave or remove.
+    reaction: String,
+}
+
+/// Leaves a reaction on a post, returning the updated reactions of the post.
+/// The thread is created if the post has no comments yet.
+#[post("/oration/thread/react?<target>")]
+fn react_thread(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    verifier: State<Verifier>,
+    target: ThreadReaction,
+    remote_addr: SocketAddr,
+) -> Result<Json<Tally>, status::Custom<Json<Refusal>>> {
+    check_reaction(&config, &target.reaction)?;
+    let tid = match threads::gen_or_get_id(
+        &conn,
+        &site,
+        "",
+        &target.url,
+        &config.threads,
+        &verifier,
+    ) {
+        Ok(tid) => tid,
+        Err(errors::Error(errors::ErrorKind::PathCheckFailed, _)) => {
+            return Err(refuse(
+                Status::Forbidden,
+                "This post does not exist on the blog.",
+            ));
+        }
+        Err(err) => {
+            print_errors(&err);
+            return Err(refuse(
+                Status::InternalServerError,
+                "Unable to read thread information.",
+            ));
+        }
+    };
+    check_thread_open(&conn, tid, config.threads.close_after)?;
+    let voter = votes::voter_identity(&remote_addr.ip().to_string(), &config.salt);
+    match reactions::add_to_thread(&conn, tid, &voter, &target.reaction) {
+        Ok(tally) => Ok(Json(tally)),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to record your reaction.",
+            ))
+        }
+    }
+}
+
+/// Removes a reaction the current user left on a post, returning the updated reactions
+/// of the post.
+#[delete("/oration/thread/react?<target>")]
+fn unreact_thread(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    target: ThreadReaction,
+    remote_addr: SocketAddr,
+) -> Result<Json<Tally>, status::Custom<Json<Refusal>>> {
+    let tid = match threads::get_id(&conn, &site.name, &target.url, &config.threads.normalise) {
+        Ok(tid) => tid,
+        Err(err) => {
+            print_errors(&err);
+            return Err(refuse(Status::NotFound, "Unable to find the requested post."));
+        }
+    };
+    check_thread_open(&conn, tid, config.threads.close_after)?;
+    let voter = votes::voter_identity(&remote_addr.ip().to_string(), &config.salt);
+    match reactions::remove_from_thread(&conn, tid, &voter, &target.reaction) {
+        Ok(tally) => Ok(Json(tally)),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to remove your reaction.",
+            ))
+        }
+    }
+}
+
+/// Reports a comment to the admin, so long as the current user has not done so already.
+/// If the comment is flagged often enough, it is moved into moderation.
+#[post("/oration/flag?<identifier>", data = "<flag>")]
+fn flag_comment(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    cache: State<CountCache>,
+    cipher: State<Cipher>,
     identifier: CommentId,
+    flag: Result<Form<FormFlag>, Option<String>>,
     remote_addr: SocketAddr,
-) -> Result<String, status::Custom<String>> {
+) -> Result<String, status::Custom<Json<Refusal>>> {
+    let reason = match flag {
+        Ok(f) => f.into_inner().reason,
+        Err(_) => {
+            //The form request was malformed, 400
+            return Err(refuse(Status::BadRequest, "The flag form was malformed."));
+        }
+    };
+    //Notifications are sent to the site the comment was left on
+    check_site(&conn, &site, identifier.id)?;
     let ip_addr = remote_addr.ip().to_string();
-    match Comment::vote(&conn, identifier.id, &ip_addr, false) {
-        Ok(_) => Ok(identifier.id.to_string()),
+    let count = match flags::insert(&conn, identifier.id, &ip_addr, &config.salt, &reason) {
+        Ok(count) => count,
+        Err(err) => {
+            print_errors(&err);
+            return Err(match err {
+                errors::Error(errors::ErrorKind::NoComment(_), _) => {
+                    refuse(Status::NotFound, "Unable to find the requested comment.")
+                }
+                errors::Error(errors::ErrorKind::AlreadyFlagged, _) => {
+                    refuse(Status::Forbidden, "You have already flagged this comment.")
+                }
+                _ => refuse(Status::InternalServerError, "Unable to record your flag."),
+            });
+        }
+    };
+
+    let limit = config.moderation.flag_limit;
+    let moderated = match flags::enforce_limit(&conn, identifier.id, count, limit) {
+        Ok(moderated) => {
+            if moderated {
+                cache.clear();
+            }
+            moderated
+        }
+        Err(err) => {
+            print_errors(&err);
+            false
+        }
+    };
+
+    if site.notifications.flagged_comment || site.telegram.push_notifications {
+        match flags::report(&conn, identifier.id, &reason, moderated) {
+            Ok(report) => {
+                if site.notifications.flagged_comment {
+                    let sent = smtp_password(&conn, &cipher, &site).and_then(|password| {
+                        notify::send_flag_notification(
+                            &report,
+                            &site.notifications,
+                            &site.host,
+                            &site.blog_name,
+                            &password,
+                        )
+                    });
+                    match sent {
+                        Ok(_) => log::info!(
+                            "📧  {}",
+                            Paint::blue("Flagged comment email notification sent.")
+                        ),
+                        Err(err) => {
+                            print_errors(&err);
+                        }
+                    }
+                }
+                if site.telegram.push_notifications {
+                    match notify::push_telegram_flag(&report, &site.telegram, &site.host) {
+                        Ok(_) => log::info!(
+                            "📧  {}",
+                            Paint::blue("Flagged comment push notification sent to Telegram.")
+                        ),
+                        Err(err) => {
+                            print_errors(&err);
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                print_errors(&err);
+            }
+        }
+    }
+
+    Ok(identifier.id.to_string())
+}
+
+/// Approves a comment which is awaiting moderation. If it was held as their first comment,
+/// the commentor is trusted from then on, so their future comments will be published immediately.
+#[post("/oration/admin/approve?<identifier>")]
+fn approve_comment(
+    conn: db::Conn,
+    cache: State<CountCache>,
+    _admin: Admin,
+    identifier: CommentId,
+) -> Result<String, Failure> {
+    match Comment::approve(&conn, identifier.id) {
+        Ok(_) => {
+            cache.clear();
+            Ok(identifier.id.to_string())
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
+        }
+    }
+}
+
+/// Deletes any comment, in the same way a commentor deletes their own.
+#[delete("/oration/admin/delete?<identifier>")]
+fn admin_delete_comment(
+    conn: db::Conn,
+    config: State<Config>,
+    cache: State<CountCache>,
+    _admin: Admin,
+    identifier: CommentId,
+) -> Result<String, Failure> {
+    let keep_days = config.moderation.trash_days;
+    match Comment::delete(&conn, identifier.id, audit::ADMIN, keep_days) {
+        Ok(_) => {
+            cache.clear();
+            Ok(identifier.id.to_string())
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
+        }
+    }
+}
+
+/// Lists the deleted comments of the blog which can still be restored, most recently
+/// deleted first.
+#[get("/oration/admin/trash")]
+fn get_trash(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    _admin: Admin,
+) -> Result<Json<Vec<TrashedComment>>, Failure> {
+    let trashed = trash::prune(&conn, config.moderation.trash_days)
+        .and_then(|_| trash::list(&conn, &site.name));
+    match trashed {
+        Ok(trashed) => Ok(Json(trashed)),
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::InternalServerError))
+        }
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/admin/trash/restore?`.
+struct TrashId {
+    /// The id of the entry in the trash.
+    id: i32,
+}
+
+/// Puts a deleted comment back from the trash. Returns the id of the restored comment.
+#[post("/oration/admin/trash/restore?<entry>")]
+fn restore_comment(
+    conn: db::Conn,
+    site: CurrentSite,
+    cache: State<CountCache>,
+    _admin: Admin,
+    entry: TrashId,
+) -> Result<String, Failure> {
+    match trash::restore(&conn, &site.name, entry.id, audit::ADMIN) {
+        Ok(cid) => {
+            cache.clear();
+            Ok(cid.to_string())
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
+        }
+    }
+}
+
+/// Number of audit log entries listed if no limit is requested.
+const AUDIT_DEFAULT: u32 = 50;
+/// Maximum number of audit log entries which can be listed at once.
+const AUDIT_MAX: u32 = 500;
+
+#[derive(FromForm)]
+/// Used in conjuction with `/admin/audit?`.
+struct AuditQuery {
+    /// Only list actions taken on this comment.
+    id: Option<i32>,
+    /// Number of entries to list.
+    limit: Option<u32>,
+}
+
+/// Lists who deleted or restored which comments of the blog, newest first.
+#[get("/oration/admin/audit?<query>")]
+fn get_audit_log(
+    conn: db::Conn,
+    site: CurrentSite,
+    _admin: Admin,
+    query: AuditQuery,
+) -> Result<Json<Vec<AuditEntry>>, Failure> {
+    let limit = query.limit.unwrap_or(AUDIT_DEFAULT).min(AUDIT_MAX);
+    match audit::list(&conn, &site.name, query.id, i64::from(limit)) {
+        Ok(entries) => Ok(Json(entries)),
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::InternalServerError))
+        }
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/admin/personal?`.
+struct PersonalQuery {
+    /// Email address or identifier hash of the commentor.
+    subject: String,
+}
+
+/// Exports everything stored about a commentor, identified by their email address or
+/// identifier hash, across every blog.
+#[get("/oration/admin/personal?<query>")]
+fn export_personal_data(
+    conn: db::Conn,
+    cipher: State<Cipher>,
+    _admin: Admin,
+    query: PersonalQuery,
+) -> Result<Json<PersonalData>, Failure> {
+    let subject = Subject::parse(&query.subject).ok_or(Failure(Status::BadRequest))?;
+    match privacy::export(&conn, &cipher, &subject) {
+        Ok(data) => Ok(Json(data)),
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::InternalServerError))
+        }
+    }
+}
+
+/// Erases the personal data of a commentor, identified by their email address or identifier
+/// hash, across every blog. Their comments are kept so replies to them still make sense.
+/// Returns the number of comments which were erased.
+#[delete("/oration/admin/personal?<query>")]
+fn erase_personal_data(
+    conn: db::Conn,
+    cipher: State<Cipher>,
+    _admin: Admin,
+    query: PersonalQuery,
+) -> Result<String, Failure> {
+    let subject = Subject::parse(&query.subject).ok_or(Failure(Status::BadRequest))?;
+    match privacy::erase(&conn, &cipher, &subject) {
+        Ok(erased) => Ok(erased.to_string()),
         Err(err) => {
             print_errors(&err);
-            Err(status::Custom(Status::Forbidden, identifier.id.to_string()))
+            Err(Failure(Status::InternalServerError))
+        }
+    }
+}
+
+/// Lists the previous versions of a comment, oldest first.
+#[get("/oration/admin/revisions?<identifier>")]
+fn admin_get_revisions(
+    conn: db::Conn,
+    _admin: Admin,
+    identifier: CommentId,
+) -> Result<Json<Vec<Revision>>, Failure> {
+    match revisions::list(&conn, identifier.id) {
+        Ok(list) => Ok(Json(list)),
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::InternalServerError))
+        }
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/admin/revisions/restore?`.
+struct RevisionRestore {
+    /// The id of the comment.
+    id: i32,
+    /// The id of the revision to roll back to.
+    revision: i32,
+}
+
+/// Rolls a comment back to one of its previous versions.
+#[post("/oration/admin/revisions/restore?<restore>")]
+fn restore_revision(
+    conn: db::Conn,
+    cache: State<CountCache>,
+    _admin: Admin,
+    restore: RevisionRestore,
+) -> Result<String, Failure> {
+    match revisions::restore(&conn, restore.id, restore.revision) {
+        Ok(_) => {
+            cache.clear();
+            Ok(restore.id.to_string())
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
+        }
+    }
+}
+
+/// Closes a thread to new comments, votes and edits.
+#[post("/oration/admin/close?<post>")]
+fn close_thread(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    _admin: Admin,
+    post: Post,
+) -> Result<String, Failure> {
+    match threads::get_id(&conn, &site.name, &post.url, &config.threads.normalise)
+        .and_then(|tid| threads::set_closed(&conn, tid, true))
+    {
+        Ok(_) => Ok(post.url),
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
+        }
+    }
+}
+
+/// Reopens a thread, whether it was closed manually or automatically after `close_after` days.
+#[post("/oration/admin/open?<post>")]
+fn open_thread(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    _admin: Admin,
+    post: Post,
+) -> Result<String, Failure> {
+    match threads::get_id(&conn, &site.name, &post.url, &config.threads.normalise)
+        .and_then(|tid| threads::set_closed(&conn, tid, false))
+    {
+        Ok(_) => Ok(post.url),
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
+        }
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/admin/thread/rename?`.
+struct ThreadRename {
+    /// The current url of the thread.
+    url: String,
+    /// The url the thread should move to.
+    to: String,
+}
+
+/// Moves a thread to a new url, keeping the current url as an alias.
+#[post("/oration/admin/thread/rename?<rename>")]
+fn rename_thread(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    cache: State<CountCache>,
+    _admin: Admin,
+    rename: ThreadRename,
+) -> Result<String, Failure> {
+    let rules = &config.threads.normalise;
+    match threads::get_id(&conn, &site.name, &rename.url, rules)
+        .and_then(|tid| threads::rename(&conn, tid, &rename.to, rules))
+    {
+        Ok(_) => {
+            cache.clear();
+            Ok(rename.to)
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::Conflict))
+        }
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/admin/thread/alias?`.
+struct ThreadAlias {
+    /// The url of the thread.
+    url: String,
+    /// An additional url for the thread.
+    alias: String,
+}
+
+/// Attaches an additional url, usually an old location of the post, to a thread.
+#[post("/oration/admin/thread/alias?<alias>")]
+fn alias_thread(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    cache: State<CountCache>,
+    _admin: Admin,
+    alias: ThreadAlias,
+) -> Result<String, Failure> {
+    let rules = &config.threads.normalise;
+    match threads::get_id(&conn, &site.name, &alias.url, rules)
+        .and_then(|tid| threads::add_alias(&conn, tid, &alias.alias, rules))
+    {
+        Ok(_) => {
+            cache.clear();
+            Ok(alias.alias)
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::Conflict))
+        }
+    }
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/admin/thread/merge?`.
+struct ThreadMerge {
+    /// The url of the thread to merge and remove.
+    url: String,
+    /// The url of the thread which receives the comments.
+    into: String,
+}
+
+/// Merges one thread into another, moving all of its comments across.
+#[post("/oration/admin/thread/merge?<merge>")]
+fn merge_threads(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    cache: State<CountCache>,
+    _admin: Admin,
+    merge: ThreadMerge,
+) -> Result<String, Failure> {
+    let rules = &config.threads.normalise;
+    let from = threads::get_id(&conn, &site.name, &merge.url, rules);
+    let into = threads::get_id(&conn, &site.name, &merge.into, rules);
+    match from.and_then(|from| into.and_then(|into| threads::merge(&conn, from, into))) {
+        Ok(_) => {
+            cache.clear();
+            Ok(merge.into)
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::NotFound))
+        }
+    }
+}
+
+/// Reads the title of every post on the blog, updating any thread titles which have changed.
+/// Returns the number of threads which were updated.
+#[post("/oration/admin/titles")]
+fn resync_titles(conn: db::Conn, site: CurrentSite, _admin: Admin) -> Result<String, Failure> {
+    match threads::resync_titles(&conn, &site) {
+        Ok(updated) => Ok(updated.to_string()),
+        Err(err) => {
+            print_errors(&err);
+            Err(Failure(Status::InternalServerError))
         }
     }
 }
@@ -321,27 +1290,105 @@ fn get_session(conn: db::Conn) -> String {
 }
 
 #[derive(FromForm)]
-/// Used in conjuction with `/count?` and `/comments?`.
+/// Used in conjuction with `/count?`, `/admin/close?` and `/admin/open?`.
 struct Post {
     /// Gets the url for the request.
     url: String,
 }
 
+#[derive(FromForm)]
+/// Used in conjuction with `/comments?`.
+struct PostListing {
+    /// Gets the url for the request.
+    url: String,
+    /// Order of the comments: oldest (the default), newest, top, best, controversial or hot.
+    sort: Option<SortOrder>,
+    /// Page of comments to list, starting at 0.
+    page: Option<u32>,
+    /// Levels of replies to include below each comment. Unlimited if not given.
+    depth: Option<u32>,
+    /// Lists the replies to this comment rather than the whole thread. Used to load replies
+    /// which were cut off by `depth`.
+    parent: Option<i32>,
+}
+
 #[derive(Serialize)]
 /// Comments to frontend
 struct PostComments {
     /// A nested set of comments.
     comments: Vec<NestedComment>,
+    /// Number of comments at the listed level, over all pages.
+    total: usize,
+    /// The next page to request, if there are more comments.
+    next_page: Option<u32>,
+    /// Number of readers who left each reaction on the post.
+    reactions: Tally,
+    /// If the thread is closed to new comments.
+    closed: bool,
 }
 
 /// Return a json block of comment data for the requested url.
+/// The `x-auth-hash` header is optional here, but if given, allows shadow banned
+/// commentors to see their own comments.
 #[get("/oration/comments?<post>")]
-fn get_comments(conn: db::Conn, post: Post) -> Option<Json<PostComments>> {
-    //TODO: The logic here may not 100%, need to consider / vs /index.* for example.
-    match NestedComment::list(&conn, &post.url) {
-        Ok(comments) => {
+fn get_comments(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    post: PostListing,
+    remote_addr: SocketAddr,
+    hash: Option<AuthHash>,
+) -> Option<Json<PostComments>> {
+    let ip_addr = remote_addr.ip().to_string();
+    let stored_addr = config.stored_ip(&ip_addr);
+    let viewer_hash = hash.as_ref().map(|h| h.as_str());
+    let tid = match threads::get_id(&conn, &site.name, &post.url, &config.threads.normalise) {
+        Ok(tid) => tid,
+        Err(errors::Error(errors::ErrorKind::NoThread(_), _)) => {
+            //A thread that doesn't exist yet has no comments, and is open for its first one
+            return Some(Json(PostComments {
+                comments: Vec::new(),
+                total: 0,
+                next_page: None,
+                reactions: Tally::new(),
+                closed: false,
+            }));
+        }
+        Err(err) => {
+            print_errors(&err);
+            return None;
+        }
+    };
+    let listing = Listing {
+        sort: post.sort.unwrap_or(SortOrder::Oldest),
+        parent: post.parent,
+        page: post.page.unwrap_or(0),
+        page_size: config.threads.page_size,
+        depth: post.depth,
+    };
+    match NestedComment::list(&conn, tid, &ip_addr, &stored_addr, viewer_hash, &listing) {
+        Ok(CommentPage {
+            comments,
+            total,
+            next_page,
+        }) => {
             //We now have a vector of comments
-            let to_send = PostComments { comments };
+            let closed = threads::is_closed(&conn, tid, config.threads.close_after)
+                .unwrap_or_else(|err| {
+                    print_errors(&err);
+                    false
+                });
+            let reactions = reactions::for_thread(&conn, tid).unwrap_or_else(|err| {
+                print_errors(&err);
+                Tally::new()
+            });
+            let to_send = PostComments {
+                comments,
+                total,
+                next_page,
+                reactions,
+                closed,
+            };
             Some(Json(to_send))
         }
         Err(err) => {
@@ -353,13 +1400,313 @@ fn get_comments(conn: db::Conn, post: Post) -> Option<Json<PostComments>> {
 
 /// Returns the comment count for a given post from the database.
 #[get("/oration/count?<post>")]
-fn get_comment_count(conn: db::Conn, post: Post) -> String {
-    //TODO: The logic here may not 100%, need to consider / vs /index.* for example.
-    match Comment::count(&conn, &post.url) {
-        Ok(s) => s.to_string(),
+fn get_comment_count(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    cache: State<CountCache>,
+    post: Post,
+) -> String {
+    if let Some(count) = cache.get(&site.name, &post.url) {
+        return count.to_string();
+    }
+    let count = match threads::get_id(&conn, &site.name, &post.url, &config.threads.normalise)
+        .and_then(|tid| Comment::count(&conn, tid))
+    {
+        Ok(count) => count,
+        Err(errors::Error(errors::ErrorKind::NoThread(_), _)) => 0,
         Err(err) => {
             print_errors(&err);
-            err.to_string()
+            return err.to_string();
+        }
+    };
+    cache.insert(&site.name, &post.url, count);
+    count.to_string()
+}
+
+/// Maximum number of posts whose comments can be counted in one request.
+const MAX_BATCH_COUNT: usize = 100;
+
+/// Returns the comment counts of many posts at once, such as those listed on a blog's index page.
+/// The urls of the posts are sent as a json list, and a json map from each url to its count
+/// is returned.
+#[post("/oration/counts", format = "application/json", data = "<urls>")]
+fn get_comment_counts(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    cache: State<CountCache>,
+    urls: Json<Vec<String>>,
+) -> Result<Json<BTreeMap<String, i64>>, status::Custom<Json<Refusal>>> {
+    let urls = urls.into_inner();
+    if urls.len() > MAX_BATCH_COUNT {
+        return Err(refuse(
+            Status::BadRequest,
+            "Too many posts were requested at once.",
+        ));
+    }
+
+    let mut counts = BTreeMap::new();
+    let mut uncached = Vec::new();
+    for url in urls {
+        match cache.get(&site.name, &url) {
+            Some(count) => {
+                counts.insert(url, count);
+            }
+            None => uncached.push(url),
+        }
+    }
+    if uncached.is_empty() {
+        return Ok(Json(counts));
+    }
+
+    let counted = threads::get_ids(&conn, &site.name, &uncached, &config.threads.normalise)
+        .and_then(|ids| {
+            let tids: Vec<i32> = ids.values().cloned().collect();
+            Comment::counts(&conn, &tids).map(|thread_counts| (ids, thread_counts))
+        });
+    match counted {
+        Ok((ids, thread_counts)) => {
+            for url in uncached {
+                //Posts without a thread, or without live comments, have none
+                let count = ids
+                    .get(&url)
+                    .and_then(|tid| thread_counts.get(tid))
+                    .cloned()
+                    .unwrap_or(0);
+                cache.insert(&site.name, &url, count);
+                counts.insert(url, count);
+            }
+            Ok(Json(counts))
+        }
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to count comments.",
+            ))
+        }
+    }
+}
+
+/// Number of recent comments listed if no limit is requested.
+const RECENT_DEFAULT: u32 = 10;
+/// Maximum number of recent comments which can be listed at once.
+const RECENT_MAX: u32 = 50;
+
+#[derive(FromForm)]
+/// Used in conjuction with `/recent?`.
+struct Recent {
+    /// Number of comments to list.
+    limit: Option<u32>,
+}
+
+/// Returns the latest published comments on any post of the blog, newest first.
+#[get("/oration/recent?<recent>")]
+fn get_recent_comments(
+    conn: db::Conn,
+    site: CurrentSite,
+    recent: Recent,
+) -> Option<Json<Vec<RecentComment>>> {
+    let limit = recent.limit.unwrap_or(RECENT_DEFAULT).min(RECENT_MAX);
+    match RecentComment::list(&conn, &site, i64::from(limit)) {
+        Ok(comments) => Some(Json(comments)),
+        Err(err) => {
+            print_errors(&err);
+            None
+        }
+    }
+}
+
+/// Number of search results returned if no limit is requested.
+const SEARCH_DEFAULT: u32 = 20;
+/// Maximum number of search results which can be returned at once.
+const SEARCH_MAX: u32 = 100;
+
+#[derive(FromForm)]
+/// Used in conjuction with `/search?` and `/admin/search?`.
+struct Search {
+    /// Words which must all appear in the comment, its author or the title of its post.
+    q: Option<String>,
+    /// Only find comments written by this author.
+    author: Option<String>,
+    /// Number of results to return.
+    limit: Option<u32>,
+}
+
+/// Searches the comments on a site, including hidden comments if `include_hidden` is set.
+fn find_comments(
+    conn: &db::Conn,
+    site: &Site,
+    query: &Search,
+    include_hidden: bool,
+) -> Result<Json<Vec<SearchResult>>, status::Custom<Json<Refusal>>> {
+    let terms = query.q.as_ref().map(String::as_str);
+    let author = query.author.as_ref().map(String::as_str);
+    let expression = match search::match_expression(terms, author) {
+        Some(expression) => expression,
+        None => return Err(refuse(Status::BadRequest, "Nothing to search for.")),
+    };
+    let limit = query.limit.unwrap_or(SEARCH_DEFAULT).min(SEARCH_MAX);
+    match search::search(conn, site, &expression, include_hidden, i64::from(limit)) {
+        Ok(results) => Ok(Json(results)),
+        Err(err) => {
+            print_errors(&err);
+            Err(refuse(
+                Status::InternalServerError,
+                "Unable to search comments.",
+            ))
+        }
+    }
+}
+
+/// Finds published comments mentioning a term or written by an author, with the matching
+/// part of each comment highlighted. Only available if `public_search` is enabled.
+#[get("/oration/search?<query>")]
+fn search_comments(
+    conn: db::Conn,
+    config: State<Config>,
+    site: CurrentSite,
+    query: Search,
+) -> Result<Json<Vec<SearchResult>>, status::Custom<Json<Refusal>>> {
+    if !config.public_search {
+        return Err(refuse(Status::Forbidden, "Searching comments is disabled."));
+    }
+    find_comments(&conn, &site, &query, false)
+}
+
+/// Finds every comment on the site mentioning a term or written by an author, including
+/// those which are awaiting moderation or otherwise hidden.
+#[get("/oration/admin/search?<query>")]
+fn admin_search_comments(
+    conn: db::Conn,
+    site: CurrentSite,
+    _admin: Admin,
+    query: Search,
+) -> Result<Json<Vec<SearchResult>>, status::Custom<Json<Refusal>>> {
+    find_comments(&conn, &site, &query, true)
+}
+
+/// Loads a comment and the comments above it, along with the site it was left on.
+fn comment_lineage<'c>(
+    conn: &db::Conn,
+    config: &'c Config,
+    id: i32,
+) -> Option<(Vec<CommentOnPost>, &'c Site)> {
+    match CommentOnPost::lineage(conn, id) {
+        Ok(lineage) => {
+            let site = config.site_named(&lineage.first()?.site)?;
+            Some((lineage, site))
+        }
+        Err(err) => {
+            print_errors(&err);
+            None
+        }
+    }
+}
+
+/// Sends readers to a comment on the page of the post it was left on, so links to a single
+/// comment keep working if the post moves.
+#[get("/oration/c/<id>")]
+fn comment_permalink(conn: db::Conn, config: State<Config>, id: i32) -> Option<Redirect> {
+    let (lineage, site) = comment_lineage(&conn, &config, id)?;
+    Some(Redirect::to(&lineage[0].permalink(&site.host)))
+}
+
+/// Returns a comment along with the chain of comments it replies to.
+#[get("/oration/c/<id>/context")]
+fn get_comment_context(
+    conn: db::Conn,
+    config: State<Config>,
+    id: i32,
+) -> Option<Json<CommentContext>> {
+    let (lineage, site) = comment_lineage(&conn, &config, id)?;
+    CommentContext::new(&lineage, &site.host).map(Json)
+}
+
+/// Number of comments included in a feed.
+const FEED_LENGTH: i64 = 50;
+
+/// Wraps a feed so it is served with the Atom content type.
+fn atom_response(xml: String) -> content::Content<String> {
+    content::Content(ContentType::new("application", "atom+xml"), xml)
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/feed?`. Feed readers send none of the headers a blog is otherwise
+/// picked by, so blogs listed under `sites` are named in the query.
+struct PostFeed {
+    /// The url of the post.
+    url: String,
+    /// Name of the blog the post belongs to. The default blog if not given.
+    site: Option<String>,
+}
+
+#[derive(FromForm)]
+/// Used in conjuction with `/feed/recent?`.
+struct SiteFeed {
+    /// Name of the blog. The default blog if not given.
+    site: Option<String>,
+}
+
+/// Returns an Atom feed of the latest comments on a post.
+#[get("/oration/feed?<post>")]
+fn get_thread_feed(
+    conn: db::Conn,
+    config: State<Config>,
+    post: PostFeed,
+) -> Option<content::Content<String>> {
+    let site = config.site_named(post.site.as_ref().map_or("", String::as_str))?;
+    let tid = match threads::get_id(&conn, &site.name, &post.url, &config.threads.normalise) {
+        Ok(tid) => tid,
+        Err(errors::Error(errors::ErrorKind::NoThread(_), _)) => return None,
+        Err(err) => {
+            print_errors(&err);
+            return None;
+        }
+    };
+    let feed = threads::title(&conn, tid).and_then(|title| {
+        let comments = CommentOnPost::latest(&conn, &site.name, Some(tid), FEED_LENGTH)?;
+        let title = format!("Comments on {}", title.as_ref().unwrap_or(&post.url));
+        let link = format!("{}{}", site.host.trim_right_matches('/'), post.url);
+        Ok(feed::atom(&title, &link, &site.host, &comments))
+    });
+    match feed {
+        Ok(xml) => Some(atom_response(xml)),
+        Err(err) => {
+            print_errors(&err);
+            None
+        }
+    }
+}
+
+/// Returns an Atom feed of the latest comments on any post of the blog named in the query.
+#[get("/oration/feed/recent?<feed>", rank = 1)]
+fn get_site_feed(
+    conn: db::Conn,
+    config: State<Config>,
+    feed: SiteFeed,
+) -> Option<content::Content<String>> {
+    let site = config.site_named(feed.site.as_ref().map_or("", String::as_str))?;
+    recent_feed(&conn, site)
+}
+
+/// Returns an Atom feed of the latest comments on any post of the default blog.
+#[get("/oration/feed/recent", rank = 2)]
+fn get_recent_feed(conn: db::Conn, config: State<Config>) -> Option<content::Content<String>> {
+    recent_feed(&conn, &config.site)
+}
+
+/// Builds an Atom feed of the latest comments on any post of `site`.
+fn recent_feed(conn: &db::Conn, site: &Site) -> Option<content::Content<String>> {
+    match CommentOnPost::latest(conn, &site.name, None, FEED_LENGTH) {
+        Ok(comments) => {
+            let title = format!("Comments on {}", site.blog_name);
+            Some(atom_response(feed::atom(&title, &site.host, &site.host, &comments)))
+        }
+        Err(err) => {
+            print_errors(&err);
+            None
         }
     }
 }
@@ -376,8 +1723,14 @@ fn print_errors(err: &Error) {
 /// Exposes a connection to the database so we can set the session on startup.
 fn rocket() -> (rocket::Rocket, db::Conn, String) {
     //Load configuration data from disk
-    let config = match Config::load() {
-        Ok(c) => c,
+    let config = load_config();
+    let hosts = config
+        .all_sites()
+        .map(|site| site.host.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let verifier = match Verifier::new(&config.threads.verify) {
+        Ok(v) => v,
         Err(ref err) => {
             println!("Error loading configuration: {}", err);
             for e in err.iter().skip(1) {
@@ -386,41 +1739,146 @@ fn rocket() -> (rocket::Rocket, db::Conn, String) {
             process::exit(1)
         }
     };
-    let host = config.host.clone();
+    let cors = Cors::new(&config);
+    let cache = CountCache::new(config.threads.count_cache_ttl);
+    let cipher = Cipher::new(&config.privacy.secret);
     let pool = db::init_pool();
-    let conn = match pool.get() {
+    let conn = connect(&pool);
+    //Email addresses stored before encryption was introduced are encrypted before serving
+    match privacy::seal_emails(&conn, &cipher) {
+        Ok(0) => {}
+        Ok(count) => log::info!(
+            "🔒  {} {}",
+            Paint::purple("Encrypted stored email addresses:"),
+            count
+        ),
+        Err(err) => {
+            print_errors(&err);
+            process::exit(1);
+        }
+    }
+    //Expired IP addresses are only purged once the service is running
+    let purge_pool = pool.clone();
+    let retention_days = config.privacy.ip_retention;
+    let purge_salt = config.salt.clone();
+    let development_secret = config.privacy.secret == DEVELOPMENT_SECRET;
+    let rocket = rocket::ignite()
+        .attach(cors)
+        .attach(AdHoc::on_launch(move |_| {
+            if development_secret {
+                log::warn!(
+                    "🔑  {}",
+                    Paint::yellow("Replace the development privacy secret before deploying")
+                );
+            }
+        }))
+        .attach(AdHoc::on_launch(move |_| {
+            retention::spawn(purge_pool.clone(), retention_days, purge_salt.clone())
+        }))
+        .manage(pool)
+        .manage(config)
+        .manage(verifier)
+        .manage(cache)
+        .manage(cipher)
+        .mount(
+            "/",
+            routes![
+                index, //TODO: index and static_files should not be managed by oration
+                static_files::files,
+                new_comment,
+                delete_comment,
+                edit_comment,
+                like_comment,
+                dislike_comment,
+                flag_comment,
+                react_comment,
+                unreact_comment,
+                react_thread,
+                unreact_thread,
+                approve_comment,
+                admin_delete_comment,
+                get_trash,
+                restore_comment,
+                get_audit_log,
+                export_personal_data,
+                erase_personal_data,
+                get_revisions,
+                admin_get_revisions,
+                restore_revision,
+                close_thread,
+                open_thread,
+                rename_thread,
+                alias_thread,
+                merge_threads,
+                resync_titles,
+                verify_email,
+                initialise,
+                get_session,
+                get_comment_count,
+                get_comment_counts,
+                get_recent_comments,
+                search_comments,
+                admin_search_comments,
+                comment_permalink,
+                get_comment_context,
+                get_thread_feed,
+                get_site_feed,
+                get_recent_feed,
+                get_comments,
+            ],
+        );
+
+    (rocket, conn, hosts)
+}
+
+/// Loads configuration data from disk, exiting if it cannot be used.
+fn load_config() -> Config {
+    match Config::load() {
+        Ok(c) => c,
+        Err(ref err) => {
+            println!("Error loading configuration: {}", err);
+            for e in err.iter().skip(1) {
+                println!("caused by: {}", e);
+            }
+            process::exit(1)
+        }
+    }
+}
+
+/// Obtains a connection from the database pool, exiting if this is not possible.
+fn connect(pool: &db::Pool) -> db::Conn {
+    match pool.get() {
         Ok(p) => db::Conn(p),
         Err(err) => {
             println!("Could not connect to database: {}", err);
             process::exit(1)
         }
-    };
-    let rocket = rocket::ignite().manage(pool).manage(config).mount(
-        "/",
-        routes![
-            index, //TODO: index and static_files should not be managed by oration
-            static_files::files,
-            new_comment,
-            delete_comment,
-            edit_comment,
-            like_comment,
-            dislike_comment,
-            initialise,
-            get_session,
-            get_comment_count,
-            get_comments,
-        ],
-    );
-
-    (rocket, conn, host)
+    }
 }
 
 /// Application entry point.
 fn main() {
     openssl_probe::init_ssl_cert_env_vars();
 
+    //Run a maintenance command instead of the web service if one is requested
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        let config = load_config();
+        let conn = connect(&db::init_pool());
+        match commands::run(&args, &conn, &config) {
+            Ok(_) => process::exit(0),
+            Err(ref err) => {
+                println!("Error: {}", err);
+                for e in err.iter().skip(1) {
+                    println!("caused by: {}", e);
+                }
+                process::exit(1)
+            }
+        }
+    }
+
     //Initialise webserver routes and database connection pool
-    let (rocket, conn, host) = rocket();
+    let (rocket, conn, hosts) = rocket();
 
     //Set the session info in the database
     log::info!("💿  {}", Paint::purple("Saving session hash to database"));
@@ -440,7 +1898,7 @@ fn main() {
     log::info!(
         "📢  {} {}",
         Paint::blue("Oration will serve comments to"),
-        host
+        hosts
     );
 
     //Start the web service
@@ -0,0 +1,228 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use serde_json;
+
+use errors::*;
+use schema::{audit_log, comments, threads};
+
+/// Actor recorded when an administrator acts on a comment.
+pub const ADMIN: &str = "admin";
+
+/// Changes to a comment which are recorded in the audit log.
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    /// The comment was deleted, or blanked if it had replies.
+    Delete,
+    /// The comment was restored from the trash.
+    Restore,
+}
+
+impl Action {
+    /// Name of the action, as stored in the audit log.
+    fn name(self) -> &'static str {
+        match self {
+            Action::Delete => "delete",
+            Action::Restore => "restore",
+        }
+    }
+}
+
+#[derive(Queryable, Serialize, Deserialize, Clone, Debug)]
+/// Everything needed to put a comment back exactly as it was, including the post it was on.
+pub struct Snapshot {
+    /// Primary key of the comment.
+    pub id: i32,
+    /// Name of the site the post belongs to.
+    pub site: String,
+    /// URI of the post.
+    pub uri: String,
+    /// Title of the post.
+    pub title: Option<String>,
+    /// Parent comment.
+    pub parent: Option<i32>,
+    /// Timestamp of creation.
+    pub created: NaiveDateTime,
+    /// Timestamp of the last edit.
+    pub modified: Option<NaiveDateTime>,
+    /// Moderation mode of the comment.
+    pub mode: i32,
+    /// Remote IP.
+    pub remote_addr: Option<String>,
+    /// Actual comment.
+    pub text: String,
+    /// Commentors author if given.
+    pub author: Option<String>,
+    /// Commentors email address if given, encrypted.
+    pub email: Option<String>,
+    /// Blind index of the commentors email address. Missing from snapshots taken before
+    /// email addresses were encrypted.
+    #[serde(default)]
+    pub email_index: Option<String>,
+    /// Commentors website if given.
+    pub website: Option<String>,
+    /// Commentors indentifier.
+    pub hash: String,
+    /// Number of likes a comment has recieved.
+    pub likes: Option<i32>,
+    /// Number of dislikes a comment has recieved.
+    pub dislikes: Option<i32>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the comment `cid` as it currently is.
+    pub fn take(conn: &SqliteConnection, cid: i32) -> QueryResult<Snapshot> {
+        comments::table
+            .inner_join(threads::table)
+            .select((
+                comments::id,
+                threads::site,
+                threads::uri,
+                threads::title,
+                comments::parent,
+                comments::created,
+                comments::modified,
+                comments::mode,
+                comments::remote_addr,
+                comments::text,
+                comments::author,
+                comments::email,
+                comments::email_index,
+                comments::website,
+                comments::hash,
+                comments::likes,
+                comments::dislikes,
+            ))
+            .filter(comments::id.eq(cid))
+            .first::<Snapshot>(conn)
+    }
+
+    /// Encodes the snapshot for storage.
+    pub fn encode(&self) -> QueryResult<String> {
+        serde_json::to_string(self)
+            .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))
+    }
+
+    /// Decodes a stored snapshot.
+    pub fn decode(stored: &str) -> Result<Snapshot> {
+        serde_json::from_str(stored).chain_err(|| ErrorKind::DBRead)
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "audit_log"]
+/// Insertable reference to the audit_log table.
+struct NewEntry<'a> {
+    /// Reference to the comment acted on.
+    cid: i32,
+    /// Name of the site the comment belongs to.
+    site: &'a str,
+    /// Who acted on the comment: the commentors identifier, or `admin`.
+    actor: &'a str,
+    /// What was done to the comment.
+    action: &'a str,
+    /// The comment at the time.
+    snapshot: String,
+    /// Timestamp of the action.
+    created: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug)]
+/// Queryable reference to the audit_log table, for a single site.
+struct StoredEntry {
+    /// Primary key.
+    id: i32,
+    /// Reference to the comment acted on.
+    cid: i32,
+    /// Who acted on the comment.
+    actor: String,
+    /// What was done to the comment.
+    action: String,
+    /// The comment at the time.
+    snapshot: String,
+    /// Timestamp of the action.
+    created: NaiveDateTime,
+}
+
+#[derive(Serialize, Debug)]
+/// An action taken on a comment, as shown to administrators.
+pub struct AuditEntry {
+    /// Primary key.
+    id: i32,
+    /// Reference to the comment acted on.
+    cid: i32,
+    /// Who acted on the comment: the commentors identifier, or `admin`.
+    actor: String,
+    /// What was done to the comment.
+    action: String,
+    /// Timestamp of the action.
+    created: DateTime<Utc>,
+    /// The comment as it was when deleted, or once restored.
+    snapshot: Snapshot,
+}
+
+/// Records that `actor` took `action` on the comment captured in `snapshot`. Called from
+/// within the transaction which takes the action.
+pub fn record(
+    conn: &SqliteConnection,
+    snapshot: &Snapshot,
+    actor: &str,
+    action: Action,
+) -> QueryResult<()> {
+    let entry = NewEntry {
+        cid: snapshot.id,
+        site: &snapshot.site,
+        actor,
+        action: action.name(),
+        snapshot: snapshot.encode()?,
+        created: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(audit_log::table)
+        .values(&entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Returns up to `limit` of the latest actions taken on comments of the site `site_name`,
+/// newest first. Only actions on the comment `cid` are returned if it is given.
+pub fn list(
+    conn: &SqliteConnection,
+    site_name: &str,
+    cid: Option<i32>,
+    limit: i64,
+) -> Result<Vec<AuditEntry>> {
+    let mut query = audit_log::table
+        .select((
+            audit_log::id,
+            audit_log::cid,
+            audit_log::actor,
+            audit_log::action,
+            audit_log::snapshot,
+            audit_log::created,
+        ))
+        .filter(audit_log::site.eq(site_name))
+        .into_boxed();
+    if let Some(cid) = cid {
+        query = query.filter(audit_log::cid.eq(cid));
+    }
+    let entries = query
+        .order(audit_log::id.desc())
+        .limit(limit)
+        .load::<StoredEntry>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            Ok(AuditEntry {
+                id: entry.id,
+                cid: entry.cid,
+                actor: entry.actor,
+                action: entry.action,
+                created: DateTime::from_utc(entry.created, Utc),
+                snapshot: Snapshot::decode(&entry.snapshot)?,
+            })
+        })
+        .collect()
+}
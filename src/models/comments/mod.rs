@@ -1,20 +1,29 @@
-use bincode::{deserialize, serialize};
-use bloomfilter::Bloom;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use crypto::digest::Digest;
 use crypto::sha2::Sha224;
 use diesel;
-use diesel::expression::dsl::sql;
 use diesel::prelude::*;
-use diesel::sql_types::Integer;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Integer};
 use diesel::sqlite::SqliteConnection;
 use itertools::join;
 use petgraph::graphmap::DiGraphMap;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::str;
 
-use data::{AuthHash, FormEdit, FormInput};
+use cipher::Cipher;
+use config::{Moderation, Site};
+use data::{AuthHash, FormEdit, FormInput, SortOrder};
 use errors::*;
-use schema::comments;
+use models::audit::{self, Action, Snapshot};
+use models::reactions::{self, Tally};
+use models::{emails, revisions, trash, trusted, votes};
+use render::excerpt;
+use schema::{comment_revisions, comments, threads};
+
+/// Scores used to rank comments from their votes.
+pub mod ranking;
 
 #[derive(Queryable, Debug)]
 /// Queryable reference to the comments table.
@@ -37,7 +46,7 @@ pub struct Comment {
     text: String,
     /// Commentors author if given.
     author: Option<String>,
-    /// Commentors email address if given.
+    /// Commentors email address if given, encrypted.
     email: Option<String>,
     /// Commentors website if given.
     website: Option<String>,
@@ -47,8 +56,13 @@ pub struct Comment {
     likes: Option<i32>, //TODO: I know the tables like i32s, but these really should be unsigned
     /// Number of dislikes a comment has recieved.
     dislikes: Option<i32>,
-    /// Who are the voters on this comment.
+    /// Unused, voters were recorded here before the votes table existed.
     voters: Option<Vec<u8>>,
+    /// Blind index of the commentors email address.
+    email_index: Option<String>,
+    /// Hash of the token kept by the browser the comment was sent from, whilst it waits on
+    /// verification.
+    browser: Option<String>,
 }
 
 #[derive(Insertable, Debug)]
@@ -67,7 +81,10 @@ struct NewComment<'c> {
     /// If the admin has reviews turned on, all new comments will be flagged as mode 1, or
     /// will be set with a default mode 0 if this feature is not enabled. A comment with mode
     /// 2 indicates this comment is `deleted`, although it contains responses below it. The
-    /// deleted comment with therefore be handled differently.
+    /// deleted comment with therefore be handled differently. Mode 3 comments are from shadow
+    /// banned commentors, and are only ever shown to their author. Mode 4 comments are waiting
+    /// for their commentor to verify the email address they supplied. Mode 5 comments were
+    /// live until readers flagged them too often, and are held until the admin reviews them.
     mode: i32,
     /// Remote IP.
     remote_addr: Option<&'c str>,
@@ -75,7 +92,7 @@ struct NewComment<'c> {
     text: &'c str,
     /// Commentors author if given.
     author: Option<String>,
-    /// Commentors email address if given.
+    /// Commentors email address if given, encrypted.
     email: Option<String>,
     /// Commentors website if given.
     website: Option<String>,
@@ -85,18 +102,22 @@ struct NewComment<'c> {
     likes: Option<i32>,
     /// Number of dislikes a comment has recieved.
     dislikes: Option<i32>,
-    /// Who are the voters on this comment.
+    /// Unused, voters were recorded here before the votes table existed.
     voters: Option<Vec<u8>>,
+    /// Blind index of the commentors email address.
+    email_index: Option<String>,
+    /// Hash of the token kept by the browser the comment was sent from, whilst it waits on
+    /// verification.
+    browser: Option<String>,
 }
 
 impl Comment {
-    /// Returns the number of comments for a given post denoted via the `path` variable.
-    pub fn count(conn: &SqliteConnection, path: &str) -> Result<i64> {
-        use schema::threads;
-
+    /// Returns the number of comments for a given thread denoted via the `tid` variable.
+    /// Only live comments are counted: deleted comments, those awaiting moderation or
+    /// verification, and those of shadow banned commentors are not.
+    pub fn count(conn: &SqliteConnection, tid: i32) -> Result<i64> {
         let comment_count = comments::table
-            .inner_join(threads::table)
-            .filter(threads::uri.eq(path))
+            .filter(comments::tid.eq(tid).and(comments::mode.eq(0)))
             .count()
             .first(conn)
             .chain_err(|| ErrorKind::DBRead)?;
@@ -104,40 +125,78 @@ impl Comment {
         Ok(comment_count)
     }
 
-    /// Stores a new comment into the database.
+    /// Returns the number of live comments in each of the threads `tids`, as `count` does.
+    /// Threads without any live comments are left out.
+    pub fn counts(conn: &SqliteConnection, tids: &[i32]) -> Result<HashMap<i32, i64>> {
+        if tids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        //The ids are all integers, so can safely be written into the query
+        let query = format!(
+            "SELECT tid, COUNT(*) AS count FROM comments WHERE mode = 0 AND tid IN ({}) GROUP BY tid",
+            join(tids.iter(), ", ")
+        );
+        let counts = sql_query(query)
+            .load::<ThreadCount>(conn)
+            .chain_err(|| ErrorKind::DBRead)?;
+        Ok(counts.into_iter().map(|c| (c.tid, c.count)).collect())
+    }
+
+    /// Stores a new comment into the database. The commentor is identified by their `ip_addr`,
+    /// whereas `stored_addr` is what is kept of it: the address itself or an HMAC of it.
     pub fn insert<'c>(
         conn: &SqliteConnection,
         tid: i32,
+        cipher: &Cipher,
         form: &FormInput,
-        ip_addr: &'c str,
+        ip_addr: &str,
+        stored_addr: &'c str,
         nesting_limit: u32,
+        moderation: &Moderation,
     ) -> Result<InsertedComment> {
         let time = Utc::now().naive_utc();
 
-        let ip = if ip_addr.is_empty() {
+        let ip = if stored_addr.is_empty() {
             None //TODO: I wonder if this is ever true?
         } else {
-            Some(ip_addr)
+            Some(stored_addr)
         };
 
         let parent_id = nesting_check(conn, form.parent, nesting_limit)?;
         let hash = gen_hash(&form.name, &form.email, &form.url, Some(ip_addr));
+        let browser = form.browser.as_ref().map(String::as_str);
+        let verified = match form.email {
+            Some(ref address) => emails::is_verified(conn, cipher, address, browser)?,
+            None => false,
+        };
+        let email = form.email.as_ref().map(String::as_str);
+        let mode = if moderation.shadow_ban.matches(&hash, ip_addr, email) {
+            3
+        } else if moderation.verify_email && form.email.is_some() && !verified {
+            4
+        } else if moderation.first_comment && !verified && !trusted::contains(conn, &hash)? {
+            1
+        } else {
+            0
+        };
 
         let c = NewComment {
             tid,
             parent: parent_id,
             created: time,
             modified: None,
-            mode: 0,
+            mode,
             remote_addr: ip,
             text: &form.comment,
             author: form.name.clone(),
-            email: form.email.clone(),
+            email: seal(cipher, &form.email)?,
             website: form.url.clone(),
             hash,
             likes: None,
             dislikes: None,
             voters: None,
+            email_index: form.email.as_ref().map(|address| cipher.index(address)),
+            browser: None,
         };
 
         let result = diesel::insert_into(comments::table)
@@ -152,43 +211,57 @@ impl Comment {
                 .first::<i32>(conn)
                 .chain_err(|| ErrorKind::DBRead)?;
             let comment = PrintedComment::get(conn, comment_id)?;
-            Ok(InsertedComment::new(&comment))
+            Ok(InsertedComment::new(&comment, mode))
         } else {
             Err(ErrorKind::DBInsert.into())
         }
     }
 
     /// Deletes a comment if there is no children, marks as deleted if there are children.
-    pub fn delete(conn: &SqliteConnection, id: i32) -> Result<()> {
-        let children_count = comments::table
-            .filter(comments::parent.eq(id))
-            .count()
-            .first::<i64>(conn)
-            .chain_err(|| ErrorKind::DBRead)?;
-        if children_count == 0 {
-            //We can safely delete this comment entirely
-            diesel::delete(comments::table.filter(comments::id.eq(id)))
-                .execute(conn)
-                .chain_err(|| ErrorKind::DBRead)?;
-        } else {
-            //This comment must be flagged as deleted instead
-            let target = comments::table.filter(comments::id.eq(id));
-            diesel::update(target)
-                .set(&ModeDelete {
-                    mode: 2,
-                    remote_addr: None,
-                    text: String::new(),
-                    author: None,
-                    email: None,
-                    website: None,
-                    hash: String::new(),
-                    likes: None,
-                    dislikes: None,
-                    voters: None,
-                })
-                .execute(conn)
-                .chain_err(|| ErrorKind::DBRead)?;
-        }
+    /// Unless `keep_days` is 0, a copy of the comment is kept in the trash for that many days
+    /// so it can be restored. The deletion is recorded in the audit log as an action of the
+    /// `actor`.
+    pub fn delete(conn: &SqliteConnection, id: i32, actor: &str, keep_days: u32) -> Result<()> {
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let snapshot = Snapshot::take(conn, id)?;
+            //Comments which were already blanked have nothing left to restore
+            if keep_days > 0 && snapshot.mode != 2 {
+                trash::put(conn, &snapshot)?;
+            }
+            audit::record(conn, &snapshot, actor, Action::Delete)?;
+            //Previous versions would otherwise outlive the deleted text. Foreign keys aren't
+            //enforced, so they don't cascade when the comment is removed entirely either
+            diesel::delete(comment_revisions::table.filter(comment_revisions::cid.eq(id)))
+                .execute(conn)?;
+
+            let children_count = comments::table
+                .filter(comments::parent.eq(id))
+                .count()
+                .first::<i64>(conn)?;
+            if children_count == 0 {
+                //We can safely delete this comment entirely
+                diesel::delete(comments::table.filter(comments::id.eq(id))).execute(conn)?;
+            } else {
+                //This comment must be flagged as deleted instead
+                let target = comments::table.filter(comments::id.eq(id));
+                diesel::update(target)
+                    .set(&ModeDelete {
+                        mode: 2,
+                        remote_addr: None,
+                        text: String::new(),
+                        author: None,
+                        email: None,
+                        website: None,
+                        hash: String::new(),
+                        likes: None,
+                        dislikes: None,
+                        voters: None,
+                        email_index: None,
+                    })
+                    .execute(conn)?;
+            }
+            Ok(())
+        }).chain_err(|| ErrorKind::DBRead)?;
 
         //Deleted comments may have had children before, but this request may have just
         //removed the last one of them. In that case we can completely remove the node
@@ -209,132 +282,137 @@ impl Comment {
             .execute(conn)
             .chain_err(|| ErrorKind::DBRead)?;
 
+        trash::prune(conn, keep_days)?;
         Ok(())
     }
 
     /// Updates a comment.
     pub fn update<'c>(
         conn: &SqliteConnection,
+        cipher: &Cipher,
         id: i32,
         data: &FormEdit,
         ip_addr: &'c str,
     ) -> Result<CommentEdits> {
         let target = comments::table.filter(comments::id.eq(id));
         let hash = gen_hash(&data.name, &data.email, &data.url, Some(ip_addr));
+        let email = seal(cipher, &data.email)?;
+        let email_index = data.email.as_ref().map(|address| cipher.index(address));
         let time = Utc::now().naive_utc();
-        diesel::update(target)
-            .set((
-                comments::text.eq(data.comment.to_owned()),
-                comments::author.eq(data.name.to_owned()),
-                comments::email.eq(data.email.to_owned()),
-                comments::website.eq(data.url.to_owned()),
-                comments::hash.eq(hash),
-                comments::modified.eq(Some(time)),
-            ))
-            .execute(conn)
-            .chain_err(|| ErrorKind::DBRead)?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            //Keep the previous version, so edits made after a reply can be seen
+            revisions::record(conn, id)?;
+            diesel::update(target)
+                .set((
+                    comments::text.eq(data.comment.to_owned()),
+                    comments::author.eq(data.name.to_owned()),
+                    comments::email.eq(email),
+                    comments::email_index.eq(email_index),
+                    comments::website.eq(data.url.to_owned()),
+                    comments::hash.eq(hash),
+                    comments::modified.eq(Some(time)),
+                ))
+                .execute(conn)?;
+            Ok(())
+        }).chain_err(|| ErrorKind::DBRead)?;
         let comment = PrintedComment::get(conn, id)?;
         Ok(CommentEdits::new(&comment))
     }
 
-    /// Called from the like and dislike functions and updates the vote tally for the
-    /// given comment, provided the user is able to vote on this comment.
-    /// We use the user's IP address here rather than the hash to ratelimit voting from
-    /// the same IP by changing user details or spamming hash headers.
-    pub fn vote<'c>(
-        conn: &SqliteConnection,
-        id: i32,
-        ip_addr: &'c str,
-        upvote: bool,
-    ) -> Result<()> {
-        let voters_blob = comments::table
-            .select(comments::voters)
+    /// Returns the id of the thread a comment belongs to.
+    pub fn thread(conn: &SqliteConnection, id: i32) -> Result<i32> {
+        let tid = comments::table
+            .select(comments::tid)
             .filter(comments::id.eq(id))
-            .first::<Option<Vec<u8>>>(conn)
+            .first::<i32>(conn)
             .chain_err(|| ErrorKind::DBRead)?;
+        Ok(tid)
+    }
 
-        let mut can_vote = true;
-        if let Some(voters) = voters_blob {
-            let blob: VotersBlob = deserialize(&voters).unwrap();
-            let mut bloom =
-                Bloom::from_existing(&blob.bitmap, blob.bits, blob.hashes, blob.sip_keys);
-            if bloom.check_and_set(ip_addr) {
-                //The IP is already in the database, so the user has already voted
-                //for the moment, this means once a vote is cast, we don't allow a user to change
-                //their vote
-                can_vote = false;
-            } else {
-                //The IP is not in the database, the updated filter needs to be stored
-                blob.store(conn, id)?;
-            }
-        } else {
-            // New bloomfilter with 95% success rate, give it space for 150 votes by default
-            let mut bloom = Bloom::new_for_fp_rate(150, 0.05);
-            // Add the current user's IP to the filter
-            bloom.set(ip_addr);
+    /// Returns the name of the site a comment was left on.
+    pub fn site(conn: &SqliteConnection, id: i32) -> Result<String> {
+        comments::table
+            .inner_join(threads::table)
+            .select(threads::site)
+            .filter(comments::id.eq(id))
+            .first::<String>(conn)
+            .optional()
+            .chain_err(|| ErrorKind::DBRead)?
+            .ok_or_else(|| ErrorKind::NoComment(id).into())
+    }
 
-            let blob = VotersBlob::new(&bloom);
-            blob.store(conn, id)?;
-        }
-        if can_vote {
-            let target = comments::table.filter(comments::id.eq(id));
-            // It would be nice to extract the `set` line here, but I can't seem to figure out how
-            if upvote {
-                diesel::update(target)
-                    .set(comments::likes.eq(comments::likes + 1))
-                    .execute(conn)
-                    .chain_err(|| ErrorKind::DBRead)?;
-            } else {
-                diesel::update(target)
-                    .set(comments::dislikes.eq(comments::dislikes + 1))
-                    .execute(conn)
-                    .chain_err(|| ErrorKind::DBRead)?;
-            };
-            Ok(())
-        } else {
-            Err(ErrorKind::AlreadyVoted.into())
-        }
+    /// Checks if a comment is live, and so can be seen by every reader.
+    pub fn is_live(conn: &SqliteConnection, id: i32) -> Result<bool> {
+        let live = comments::table
+            .filter(comments::id.eq(id).and(comments::mode.eq(0)))
+            .count()
+            .first::<i64>(conn)
+            .chain_err(|| ErrorKind::DBRead)?;
+        Ok(live > 0)
     }
-}
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-/// Bloom encoding for voters. Currently more a testing phase than final product.
-struct VotersBlob {
-    /// Probabilistic matrix.
-    bitmap: Vec<u8>,
-    /// Number of bits in filter.
-    bits: u64,
-    /// All hashes in the filter.
-    hashes: u32,
-    /// Required sip keys.
-    sip_keys: [(u64, u64); 2],
-}
-
-impl VotersBlob {
-    /// Generate a voters struct.
-    fn new(bloom: &Bloom) -> VotersBlob {
-        VotersBlob {
-            bitmap: bloom.bitmap(),
-            bits: bloom.number_of_bits(),
-            hashes: bloom.number_of_hash_functions(),
-            sip_keys: bloom.sip_keys(),
-        }
+    /// Moves a live comment into the moderation queue, as it has been flagged too often.
+    /// Returns true if the comment was live beforehand.
+    pub fn moderate(conn: &SqliteConnection, id: i32) -> Result<bool> {
+        let target = comments::table.filter(comments::id.eq(id).and(comments::mode.eq(0)));
+        let updated = diesel::update(target)
+            .set(comments::mode.eq(5))
+            .execute(conn)
+            .chain_err(|| ErrorKind::DBRead)?;
+        Ok(updated > 0)
     }
 
-    /// Encode the bloom filter and store it in the database.
-    fn store(self, conn: &SqliteConnection, id: i32) -> Result<()> {
-        let blob_encoded: Vec<u8> = serialize(&self).chain_err(|| ErrorKind::Serialize)?;
+    /// Publishes a comment which is awaiting moderation. If it was held as the first comment
+    /// of its commentor, they are trusted from then on so that their future comments are
+    /// published immediately. Approving a flagged comment trusts no one.
+    pub fn approve(conn: &SqliteConnection, id: i32) -> Result<()> {
+        let (hash, mode) = comments::table
+            .select((comments::hash, comments::mode))
+            .filter(comments::id.eq(id).and(comments::mode.eq_any(vec![1, 5])))
+            .first::<(String, i32)>(conn)
+            .optional()
+            .chain_err(|| ErrorKind::DBRead)?
+            .ok_or_else(|| Error::from(ErrorKind::NoComment(id)))?;
 
-        let target = comments::table.filter(comments::id.eq(id));
-        diesel::update(target)
-            .set(comments::voters.eq(blob_encoded))
+        diesel::update(comments::table.filter(comments::id.eq(id)))
+            .set(comments::mode.eq(0))
             .execute(conn)
             .chain_err(|| ErrorKind::DBRead)?;
+        if mode == 1 {
+            trusted::insert(conn, &hash)
+        } else {
+            Ok(())
+        }
+    }
 
-        Ok(())
+    /// Called from the like and dislike functions and updates the vote tally for the
+    /// given comment. Voting the same way twice retracts the vote, and voting the other way
+    /// changes it. Returns the user's vote afterwards, or `None` if it was retracted.
+    /// We use the user's IP address here (hashed with `salt`) rather than the hash to ratelimit
+    /// voting from the same IP by changing user details or spamming hash headers.
+    pub fn vote<'c>(
+        conn: &SqliteConnection,
+        id: i32,
+        ip_addr: &'c str,
+        salt: &'c str,
+        upvote: bool,
+    ) -> Result<Option<bool>> {
+        let voter = votes::voter_identity(ip_addr, salt);
+        votes::cast(conn, id, &voter, upvote)
     }
 }
 
+#[derive(QueryableByName, Debug)]
+/// Number of live comments in a thread.
+struct ThreadCount {
+    /// Reference to Thread.
+    #[sql_type = "Integer"]
+    tid: i32,
+    /// Number of live comments.
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
 #[derive(AsChangeset)]
 #[table_name = "comments"]
 #[changeset_options(treat_none_as_null = "true")]
@@ -348,7 +426,7 @@ struct ModeDelete {
     text: String,
     /// Commentors author if given.
     author: Option<String>,
-    /// Commentors email address if given.
+    /// Commentors email address if given, encrypted.
     email: Option<String>,
     /// Commentors website if given.
     website: Option<String>,
@@ -358,8 +436,40 @@ struct ModeDelete {
     likes: Option<i32>,
     /// Number of dislikes a comment has recieved.
     dislikes: Option<i32>,
-    /// Who are the voters on this comment.
+    /// Unused, voters were recorded here before the votes table existed.
     voters: Option<Vec<u8>>,
+    /// Blind index of the commentors email address.
+    email_index: Option<String>,
+}
+
+#[derive(QueryableByName, Debug)]
+/// One step up a comment's chain of parents.
+struct Ancestor {
+    /// Primary key of the ancestor.
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+/// Returns the ids of the comment `id` and every comment above it, from the comment itself up
+/// to the top level comment of its thread. Empty if there is no such comment.
+fn lineage(conn: &SqliteConnection, id: i32) -> Result<Vec<i32>> {
+    //NOTE: UNION ALL and WITH RECURSIVE are currently not supported by diesel
+    //https://github.com/diesel-rs/diesel/issues/33
+    //https://github.com/diesel-rs/diesel/issues/356
+    //So this is implemented in native SQL for the moment
+    let ancestors = sql_query(
+        "WITH RECURSIVE node_ancestors(node_id, parent_id, depth) AS (
+            SELECT id, id, 0 FROM comments WHERE id = ?
+            UNION ALL
+                SELECT na.node_id, comments.parent, na.depth + 1
+                FROM node_ancestors AS na, comments
+                WHERE comments.id = na.parent_id AND comments.parent IS NOT NULL
+            )
+            SELECT parent_id AS id FROM node_ancestors ORDER BY depth;",
+    ).bind::<Integer, _>(id)
+        .load::<Ancestor>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(ancestors.into_iter().map(|a| a.id).collect())
 }
 
 /// Checks if this comment is nested too deep based on the configuration file value.
@@ -371,32 +481,9 @@ fn nesting_check(
 ) -> Result<Option<i32>> {
     match parent {
         Some(pid) => {
-            //NOTE: UNION ALL and WITH RECURSIVE are currently not supported by diesel
-            //https://github.com/diesel-rs/diesel/issues/33
-            //https://github.com/diesel-rs/diesel/issues/356
-            //So this is implemented in native SQL for the moment
-            //TODO: since SqlLiteral#bind is depreciated, we should be using `sql_query`
-            //here. However: we're building a virtual table and pulling a count from it.
-            //Diesel for the moment AFAIK is not a happy camper about this.
-            let mut query = String::from(
-                "WITH RECURSIVE node_ancestors(node_id, parent_id) AS (
-                    SELECT id, id FROM comments WHERE id = ",
-            );
-            query.push_str(&pid.to_string());
-            query.push_str(
-                "
-                UNION ALL
-                    SELECT na.node_id, comments.parent
-                    FROM node_ancestors AS na, comments
-                    WHERE comments.id = na.parent_id AND comments.parent IS NOT NULL
-                )
-                SELECT COUNT(parent_id) AS depth FROM node_ancestors GROUP BY node_id;",
-            );
-            let parent_depth: Vec<i32> = sql::<Integer>(&query)
-                .load(conn)
-                .chain_err(|| ErrorKind::DBRead)?;
+            let parent_depth = lineage(conn, pid)?.len();
 
-            if parent_depth.is_empty() || parent_depth[0] <= nesting_limit as i32 {
+            if parent_depth == 0 || parent_depth <= nesting_limit as usize {
                 //We're fine to nest
                 Ok(Some(pid as i32))
             } else {
@@ -413,6 +500,14 @@ fn nesting_check(
     }
 }
 
+/// Encrypts a commentors email address, if one was given, for storage.
+fn seal(cipher: &Cipher, email: &Option<String>) -> Result<Option<String>> {
+    match *email {
+        Some(ref address) => Ok(Some(cipher.encrypt(address)?)),
+        None => Ok(None),
+    }
+}
+
 /// Generates a Sha224 hash of author details.
 /// If none are set, then the possiblity of using a clients' IP address is available.
 pub fn gen_hash(
@@ -496,14 +591,14 @@ struct PrintedComment {
     text: String,
     /// Commentors author if given.
     author: Option<String>,
-    /// Commentors email address if given.
-    email: Option<String>,
     /// Commentors website if given.
     url: Option<String>,
     /// Commentors indentifier.
     hash: String,
     /// Timestamp of creation.
     created: NaiveDateTime,
+    /// Timestamp of the last edit.
+    modified: Option<NaiveDateTime>,
     /// Number of likes a comment has recieved.
     likes: Option<i32>,
     /// Number of dislikes a comment has recieved.
@@ -511,9 +606,21 @@ struct PrintedComment {
 }
 
 impl PrintedComment {
-    /// Returns a list of all comments for a given post denoted via the `path` variable.
-    fn list(conn: &SqliteConnection, path: &str) -> Result<Vec<PrintedComment>> {
-        use schema::threads;
+    /// Returns a list of all comments for a given thread denoted via the `tid` variable.
+    /// Comments from shadow banned commentors are only returned if the viewer is the
+    /// commentor themselves. They are identified by the address stored with the comment
+    /// (`stored_addr`, the form of `ip_addr` which is kept) or by their identity: either the
+    /// `hash` they sent, or the hash of their `ip_addr` if they commented anonymously. The
+    /// identity still matches once the address is no longer stored.
+    fn list(
+        conn: &SqliteConnection,
+        tid: i32,
+        ip_addr: &str,
+        stored_addr: &str,
+        hash: Option<&str>,
+    ) -> Result<Vec<PrintedComment>> {
+        let viewer_hash = hash.unwrap_or_default();
+        let anonymous_hash = gen_hash(&None, &None, &None, Some(ip_addr));
 
         let comments: Vec<PrintedComment> = comments::table
             .select((
@@ -521,18 +628,25 @@ impl PrintedComment {
                 comments::parent,
                 comments::text,
                 comments::author,
-                comments::email,
                 comments::website,
                 comments::hash,
                 comments::created,
+                comments::modified,
                 comments::likes,
                 comments::dislikes,
             ))
-            .inner_join(threads::table)
             .filter(
-                threads::uri
-                    .eq(path)
-                    .and(comments::mode.eq(0).or(comments::mode.eq(2))),
+                comments::tid.eq(tid).and(
+                    comments::mode
+                        .eq(0)
+                        .or(comments::mode.eq(2))
+                        .or(comments::mode.eq(3).and(
+                            comments::remote_addr
+                                .eq(stored_addr)
+                                .or(comments::hash.eq(viewer_hash))
+                                .or(comments::hash.eq(anonymous_hash)),
+                        )),
+                ),
             )
             .load(conn)
             .chain_err(|| ErrorKind::DBRead)?;
@@ -547,10 +661,10 @@ impl PrintedComment {
                 comments::parent,
                 comments::text,
                 comments::author,
-                comments::email,
                 comments::website,
                 comments::hash,
                 comments::created,
+                comments::modified,
                 comments::likes,
                 comments::dislikes,
             ))
@@ -571,18 +685,81 @@ pub struct InsertedComment {
     parent: Option<i32>,
     /// Commentors details.
     author: Option<String>,
+    /// Mode the comment was stored with. Never sent to the frontend, so shadow banned
+    /// commentors are none the wiser.
+    #[serde(skip_serializing)]
+    mode: i32,
+    /// Token for the commentor's browser to keep and send along with future comments, once
+    /// it has been sent a link to verify their email address.
+    browser: Option<String>,
 }
 
 impl InsertedComment {
     /// Creates a new nested comment from a PrintedComment and a set of precalculated NestedComment children.
-    fn new(comment: &PrintedComment) -> InsertedComment {
-        let author = get_author(&comment.author, &comment.email, &comment.url);
+    fn new(comment: &PrintedComment, mode: i32) -> InsertedComment {
+        let author = get_author(&comment.author, &comment.url);
         InsertedComment {
             id: comment.id,
             parent: comment.parent,
             author,
+            mode,
+            browser: None,
         }
     }
+
+    /// Hands the commentor's browser the token their email address is being verified for: the
+    /// `token` it already keeps if any, or a new one. The comment is only published once the
+    /// address is verified from this browser. Returns the token.
+    pub fn keep_browser(
+        &mut self,
+        conn: &SqliteConnection,
+        token: Option<&str>,
+    ) -> Result<String> {
+        let token = match token {
+            Some(token) if !token.is_empty() => token.to_string(),
+            _ => emails::new_token()?,
+        };
+        let target = comments::table.filter(comments::id.eq(self.id).and(comments::mode.eq(4)));
+        diesel::update(target)
+            .set(comments::browser.eq(emails::browser_identity(&token)))
+            .execute(conn)
+            .chain_err(|| ErrorKind::DBRead)?;
+        self.browser = Some(token.clone());
+        Ok(token)
+    }
+
+    /// Moves a comment waiting on verification into the moderation queue, for when the
+    /// verification link could not be sent to its commentor.
+    pub fn await_approval(&mut self, conn: &SqliteConnection) -> Result<()> {
+        let target = comments::table.filter(comments::id.eq(self.id).and(comments::mode.eq(4)));
+        diesel::update(target)
+            .set((comments::mode.eq(1), comments::browser.eq(None::<String>)))
+            .execute(conn)
+            .chain_err(|| ErrorKind::DBRead)?;
+        self.mode = 1;
+        self.browser = None;
+        Ok(())
+    }
+
+    /// True if this comment was published straight away.
+    pub fn is_live(&self) -> bool {
+        self.mode == 0
+    }
+
+    /// True if this comment was posted by a shadow banned commentor.
+    pub fn is_shadow_banned(&self) -> bool {
+        self.mode == 3
+    }
+
+    /// True if this comment is awaiting moderation.
+    pub fn is_pending(&self) -> bool {
+        self.mode == 1
+    }
+
+    /// True if this comment is waiting for its commentor to verify their email address.
+    pub fn is_unverified(&self) -> bool {
+        self.mode == 4
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -602,7 +779,7 @@ pub struct CommentEdits {
 impl CommentEdits {
     /// Creates a new nested comment from a PrintedComment and a set of precalculated NestedComment children.
     fn new(comment: &PrintedComment) -> CommentEdits {
-        let author = get_author(&comment.author, &comment.email, &comment.url);
+        let author = get_author(&comment.author, &comment.url);
         CommentEdits {
             id: comment.id,
             author,
@@ -625,98 +802,462 @@ pub struct NestedComment {
     hash: String,
     /// Timestamp of creation.
     created: DateTime<Utc>,
+    /// Timestamp of the last edit, if the comment has been edited.
+    edited: Option<DateTime<Utc>>,
     /// Comment children.
     children: Vec<NestedComment>,
+    /// Number of direct replies which were cut off by the depth limit. These can be loaded by
+    /// listing again with this comment as the parent.
+    more_replies: usize,
     /// Total number of votes.
     votes: i32,
+    /// Wilson score of the comment's votes, used by the `best` sort order.
+    best: f64,
+    /// How evenly split a large number of votes are, used by the `controversial` sort order.
+    controversial: f64,
+    /// Votes weighted toward newer comments, used by the `hot` sort order.
+    hot: f64,
+    /// Number of readers who left each reaction.
+    reactions: Tally,
+}
+
+/// Selects which part of a thread is listed, and how.
+#[derive(Debug)]
+pub struct Listing {
+    /// Order of comments at every level of the tree.
+    pub sort: SortOrder,
+    /// Comment whose replies are listed, or the whole thread if `None`.
+    pub parent: Option<i32>,
+    /// Page of comments directly below `parent` to list, starting at 0.
+    pub page: u32,
+    /// Number of comments per page. 0 lists every comment on one page.
+    pub page_size: u32,
+    /// Number of levels of replies to include below each listed comment. Unlimited if `None`.
+    pub depth: Option<u32>,
+}
+
+/// A page of nested comments.
+#[derive(Debug)]
+pub struct CommentPage {
+    /// Comments on this page, along with their replies.
+    pub comments: Vec<NestedComment>,
+    /// Number of comments directly below the listed parent, over all pages.
+    pub total: usize,
+    /// The following page, if there is one.
+    pub next_page: Option<u32>,
 }
 
 impl NestedComment {
     /// Creates a new nested comment from a PrintedComment and a set of precalculated NestedComment children.
-    fn new(comment: &PrintedComment, children: Vec<NestedComment>) -> NestedComment {
+    fn new(
+        comment: &PrintedComment,
+        children: Vec<NestedComment>,
+        more_replies: usize,
+        reactions: Tally,
+    ) -> NestedComment {
         let date_time = DateTime::<Utc>::from_utc(comment.created, Utc);
-        let author = get_author(&comment.author, &comment.email, &comment.url);
+        let author = get_author(&comment.author, &comment.url);
         let votes = count_votes(comment.likes, comment.dislikes);
+        let (likes, dislikes) = (comment.likes.unwrap_or(0), comment.dislikes.unwrap_or(0));
         NestedComment {
             id: comment.id,
             text: comment.text.to_owned(),
             author,
             hash: comment.hash.to_owned(),
             created: date_time,
+            edited: comment.modified.map(|m| DateTime::from_utc(m, Utc)),
             children,
+            more_replies,
             votes,
+            best: ranking::best(likes, dislikes),
+            controversial: ranking::controversial(likes, dislikes),
+            hot: ranking::hot(likes, dislikes, comment.created),
+            reactions,
         }
     }
 
-    /// Returns a list of all comments, nested, for a given post denoted via the `path` variable.
-    pub fn list(conn: &SqliteConnection, path: &str) -> Result<Vec<NestedComment>> {
+    /// Returns a page of comments, nested, for a given thread denoted via the `tid` variable.
+    /// The viewer's `ip_addr`, the form it is stored in (`stored_addr`) and their `hash` are
+    /// needed to show shadow banned commentors their own comments.
+    pub fn list(
+        conn: &SqliteConnection,
+        tid: i32,
+        ip_addr: &str,
+        stored_addr: &str,
+        hash: Option<&str>,
+        listing: &Listing,
+    ) -> Result<CommentPage> {
         // Pull data from DB
-        let comments = PrintedComment::list(conn, path)?;
-
-        let mut graph = DiGraphMap::new();
+        let comments = PrintedComment::list(conn, tid, ip_addr, stored_addr, hash)?;
+        let mut tree = Tree {
+            graph: DiGraphMap::new(),
+            index: comments
+                .iter()
+                .enumerate()
+                .map(|(idx, comment)| (comment.id, idx))
+                .collect(),
+            comments: &comments,
+            reactions: reactions::for_comments(conn, tid)?,
+            sort: listing.sort,
+        };
         let mut top_level_ids = Vec::new();
 
         for comment in &comments {
             //For each comment, build a graph of parents and children
-            graph.add_node(comment.id);
+            tree.graph.add_node(comment.id);
 
             //Generate edges if a relationship is found, stash as a root if not
             if let Some(parent_id) = comment.parent {
-                graph.add_node(parent_id);
-                graph.add_edge(parent_id, comment.id, ());
+                tree.graph.add_node(parent_id);
+                tree.graph.add_edge(parent_id, comment.id, ());
             } else {
                 top_level_ids.push(comment.id);
             }
         }
 
-        //Run over all root comments, recursively filling their children as we go
-        let tree: Vec<_> = top_level_ids
-            .into_iter()
-            .map(|id| build_tree(&graph, id, &comments))
+        let mut level_ids = match listing.parent {
+            Some(parent_id) if tree.index.contains_key(&parent_id) => {
+                tree.graph.neighbors(parent_id).collect()
+            }
+            Some(parent_id) => return Err(ErrorKind::NoComment(parent_id).into()),
+            None => top_level_ids,
+        };
+        tree.sort_ids(&mut level_ids);
+
+        let total = level_ids.len();
+        let (start, end) = if listing.page_size == 0 {
+            (0, total)
+        } else {
+            let start = (listing.page as usize).saturating_mul(listing.page_size as usize);
+            (start.min(total), start.saturating_add(listing.page_size as usize).min(total))
+        };
+        let next_page = if end < total {
+            Some(listing.page + 1)
+        } else {
+            None
+        };
+
+        //Run over all comments on this page, recursively filling their children as we go
+        let nested: Vec<_> = level_ids[start..end]
+            .iter()
+            .map(|&id| tree.build(id, listing.depth))
             .collect();
 
-        Ok(tree)
+        Ok(CommentPage {
+            comments: nested,
+            total,
+            next_page,
+        })
     }
 }
 
-/// Construct a nested comment tree from the flat indexed data obtained from the database.
-fn build_tree(graph: &DiGraphMap<i32, ()>, id: i32, comments: &[PrintedComment]) -> NestedComment {
-    let children: Vec<NestedComment> = graph
-        .neighbors(id)
-        .map(|child_id| build_tree(graph, child_id, comments))
-        .collect();
+/// Number of characters of a comment shown in excerpts.
+const EXCERPT_LENGTH: usize = 200;
 
-    //We can just unwrap here since the id value is always populated from a map over contents.
-    let idx: usize = comments.iter().position(|c| c.id == id).unwrap();
+#[derive(Queryable, Debug)]
+/// A published comment along with the post it was left on.
+pub struct CommentOnPost {
+    /// Primary key.
+    pub id: i32,
+    /// Parent comment.
+    pub parent: Option<i32>,
+    /// Actual comment.
+    pub text: String,
+    /// Commentors author if given.
+    author: Option<String>,
+    /// Commentors website if given.
+    url: Option<String>,
+    /// Commentors indentifier.
+    hash: String,
+    /// Timestamp of creation.
+    pub created: NaiveDateTime,
+    /// Timestamp of the last edit.
+    pub modified: Option<NaiveDateTime>,
+    /// Name of the site the post belongs to.
+    pub site: String,
+    /// URI of the post.
+    pub uri: String,
+    /// Title of the post.
+    pub title: Option<String>,
+}
 
-    if !children.is_empty() {
-        NestedComment::new(&comments[idx], children)
-    } else {
-        NestedComment::new(&comments[idx], Vec::new())
+impl CommentOnPost {
+    /// Returns the comment `id` followed by the comments it replies to, up to the top level
+    /// comment of its thread. Only comments shown to every reader are returned, and deleted
+    /// comments which still have replies are blank. Empty if the comment itself is not shown.
+    pub fn lineage(conn: &SqliteConnection, id: i32) -> Result<Vec<CommentOnPost>> {
+        let ids = lineage(conn, id)?;
+        let mut found = comments::table
+            .inner_join(threads::table)
+            .select((
+                comments::id,
+                comments::parent,
+                comments::text,
+                comments::author,
+                comments::website,
+                comments::hash,
+                comments::created,
+                comments::modified,
+                threads::site,
+                threads::uri,
+                threads::title,
+            ))
+            .filter(comments::id.eq_any(&ids))
+            .filter(comments::mode.eq(0).or(comments::mode.eq(2)))
+            .load::<CommentOnPost>(conn)
+            .chain_err(|| ErrorKind::DBRead)?;
+        found.sort_by_key(|c| ids.iter().position(|&i| i == c.id));
+        if found.first().map(|c| c.id) != Some(id) {
+            found.clear();
+        }
+        Ok(found)
+    }
+
+    /// Returns the `limit` most recently published comments on the site `site_name`,
+    /// newest first. Only comments of the thread `tid` are returned if it is given.
+    pub fn latest(
+        conn: &SqliteConnection,
+        site_name: &str,
+        tid: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<CommentOnPost>> {
+        let mut query = comments::table
+            .inner_join(threads::table)
+            .select((
+                comments::id,
+                comments::parent,
+                comments::text,
+                comments::author,
+                comments::website,
+                comments::hash,
+                comments::created,
+                comments::modified,
+                threads::site,
+                threads::uri,
+                threads::title,
+            ))
+            .filter(comments::mode.eq(0).and(threads::site.eq(site_name)))
+            .into_boxed();
+        if let Some(tid) = tid {
+            query = query.filter(comments::tid.eq(tid));
+        }
+        query
+            .order((comments::created.desc(), comments::id.desc()))
+            .limit(limit)
+            .load::<CommentOnPost>(conn)
+            .chain_err(|| ErrorKind::DBRead)
+    }
+
+    /// Name to show for the commentor.
+    pub fn author(&self) -> Option<String> {
+        get_author(&self.author, &self.url)
+    }
+
+    /// Anchor of the comment on the post's page.
+    pub fn anchor(&self) -> String {
+        format!("comment-{}", self.id)
+    }
+
+    /// Link to the comment on the post's page, on the blog at `host`.
+    pub fn permalink(&self, host: &str) -> String {
+        format!("{}{}#{}", host.trim_right_matches('/'), self.uri, self.anchor())
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A single comment, along with where it can be found.
+pub struct LinkedComment {
+    /// Primary key.
+    id: i32,
+    /// Parent comment.
+    parent: Option<i32>,
+    /// Actual comment.
+    text: String,
+    /// Commentors author if given.
+    author: Option<String>,
+    /// Commentors indentifier.
+    hash: String,
+    /// Timestamp of creation.
+    created: DateTime<Utc>,
+    /// Link to the comment on the post's page.
+    permalink: String,
+}
+
+impl LinkedComment {
+    /// Links a comment to its place on the blog at `host`.
+    fn new(comment: &CommentOnPost, host: &str) -> LinkedComment {
+        LinkedComment {
+            id: comment.id,
+            parent: comment.parent,
+            text: comment.text.to_owned(),
+            author: comment.author(),
+            hash: comment.hash.to_owned(),
+            created: DateTime::from_utc(comment.created, Utc),
+            permalink: comment.permalink(host),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A comment along with the chain of comments it replies to, so it can be shown on its own.
+pub struct CommentContext {
+    /// URI of the post.
+    uri: String,
+    /// Title of the post.
+    title: Option<String>,
+    /// The comment itself.
+    comment: LinkedComment,
+    /// Comments above this one, starting from the top level comment of the thread.
+    ancestors: Vec<LinkedComment>,
+}
+
+impl CommentContext {
+    /// Builds the context of the first comment of a `lineage`, left on the blog at `host`.
+    /// Returns `None` if the lineage is empty.
+    pub fn new(lineage: &[CommentOnPost], host: &str) -> Option<CommentContext> {
+        let (comment, ancestors) = lineage.split_first()?;
+        Some(CommentContext {
+            uri: comment.uri.to_owned(),
+            title: comment.title.to_owned(),
+            comment: LinkedComment::new(comment, host),
+            ancestors: ancestors
+                .iter()
+                .rev()
+                .map(|c| LinkedComment::new(c, host))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A recently published comment, listed along with the post it was left on.
+pub struct RecentComment {
+    /// Primary key.
+    id: i32,
+    /// Commentors author if given.
+    author: Option<String>,
+    /// Commentors indentifier.
+    hash: String,
+    /// Timestamp of creation.
+    created: DateTime<Utc>,
+    /// The start of the comment, as plain text.
+    excerpt: String,
+    /// Title of the post.
+    title: Option<String>,
+    /// URI of the post.
+    uri: String,
+    /// Anchor of the comment on the post's page.
+    anchor: String,
+    /// Link to the comment on the post's page.
+    permalink: String,
+}
+
+impl RecentComment {
+    /// Returns the `limit` most recently published comments on any post of the `site`.
+    pub fn list(conn: &SqliteConnection, site: &Site, limit: i64) -> Result<Vec<RecentComment>> {
+        let recent = CommentOnPost::latest(conn, &site.name, None, limit)?;
+
+        Ok(recent
+            .into_iter()
+            .map(|comment| {
+                let anchor = comment.anchor();
+                let permalink = comment.permalink(&site.host);
+                RecentComment {
+                    id: comment.id,
+                    author: comment.author(),
+                    hash: comment.hash,
+                    created: DateTime::<Utc>::from_utc(comment.created, Utc),
+                    excerpt: excerpt(&comment.text, EXCERPT_LENGTH),
+                    title: comment.title,
+                    uri: comment.uri,
+                    anchor,
+                    permalink,
+                }
+            }).collect())
+    }
+}
+
+/// Flat indexed data obtained from the database, from which nested comments are built.
+struct Tree<'a> {
+    /// Edges from each comment to its replies.
+    graph: DiGraphMap<i32, ()>,
+    /// Position of each comment in `comments`, by id.
+    index: HashMap<i32, usize>,
+    /// Every comment in the thread.
+    comments: &'a [PrintedComment],
+    /// Reactions left on each comment, by id.
+    reactions: HashMap<i32, Tally>,
+    /// Order of comments at every level of the tree.
+    sort: SortOrder,
+}
+
+impl<'a> Tree<'a> {
+    /// Construct a nested comment tree from the comment `id` down.
+    /// Replies more than `depth` levels down are cut off.
+    fn build(&self, id: i32, depth: Option<u32>) -> NestedComment {
+        let mut child_ids: Vec<i32> = self.graph.neighbors(id).collect();
+
+        //We can just index here since the id value is always populated from a map over contents.
+        let comment = self.get(id);
+        let reactions = self.reactions.get(&id).cloned().unwrap_or_default();
+
+        if depth == Some(0) {
+            return NestedComment::new(comment, Vec::new(), child_ids.len(), reactions);
+        }
+        self.sort_ids(&mut child_ids);
+        let children: Vec<NestedComment> = child_ids
+            .into_iter()
+            .map(|child_id| self.build(child_id, depth.map(|d| d - 1)))
+            .collect();
+
+        NestedComment::new(comment, children, 0, reactions)
+    }
+
+    /// Returns the comment `id`.
+    fn get(&self, id: i32) -> &'a PrintedComment {
+        &self.comments[self.index[&id]]
+    }
+
+    /// Sorts a set of sibling comments into the requested order.
+    fn sort_ids(&self, ids: &mut Vec<i32>) {
+        let oldest_first = |a: &PrintedComment, b: &PrintedComment| {
+            a.created.cmp(&b.created).then(a.id.cmp(&b.id))
+        };
+        //Highest score first, falling back to the oldest comment for equal scores
+        let by_score =
+            |a: &PrintedComment, b: &PrintedComment, score: fn(&PrintedComment) -> f64| {
+                score(b)
+                    .partial_cmp(&score(a))
+                    .unwrap_or(Ordering::Equal)
+                    .then_with(|| oldest_first(a, b))
+            };
+        ids.sort_by(|&a, &b| {
+            let (a, b) = (self.get(a), self.get(b));
+            match self.sort {
+                SortOrder::Oldest => oldest_first(a, b),
+                SortOrder::Newest => oldest_first(b, a),
+                SortOrder::Top => count_votes(b.likes, b.dislikes)
+                    .cmp(&count_votes(a.likes, a.dislikes))
+                    .then_with(|| oldest_first(a, b)),
+                SortOrder::Best => by_score(a, b, |c| {
+                    ranking::best(c.likes.unwrap_or(0), c.dislikes.unwrap_or(0))
+                }),
+                SortOrder::Controversial => by_score(a, b, |c| {
+                    ranking::controversial(c.likes.unwrap_or(0), c.dislikes.unwrap_or(0))
+                }),
+                SortOrder::Hot => by_score(a, b, |c| {
+                    ranking::hot(c.likes.unwrap_or(0), c.dislikes.unwrap_or(0), c.created)
+                }),
+            }
+        });
     }
 }
 
 /// Generates a value for author depending on the completeness of the author profile.
-fn get_author(
-    author: &Option<String>,
-    email: &Option<String>,
-    url: &Option<String>,
-) -> Option<String> {
+/// Email addresses are stored encrypted, so are never shown in place of a name.
+pub fn get_author(author: &Option<String>, url: &Option<String>) -> Option<String> {
     if author.is_some() {
         author.to_owned()
-    } else if email.is_some() {
-        //We want to parse the email address to keep it somewhat confidential.
-        let real_email = email.to_owned().unwrap();
-        let at_index = real_email.find('@').unwrap_or_else(|| real_email.len());
-        let (user, domain) = real_email.split_at(at_index);
-        let first_dot = domain.find('.').unwrap_or_else(|| domain.len());
-        let (_, trailing) = domain.split_at(first_dot);
-
-        let mut email_obf = String::new();
-        email_obf.push_str(user);
-        email_obf.push_str("@****");
-        email_obf.push_str(trailing);
-        Some(email_obf)
     } else {
         //This can be something or nothing, since we don't need te parse it it doesn't matter
         url.to_owned()
@@ -0,0 +1,46 @@
+use chrono::NaiveDateTime;
+
+/// z-score of the 95% confidence level used for the Wilson score interval.
+const CONFIDENCE_Z: f64 = 1.96;
+/// Start of time for hot scores (2017-07-19, the first Oration commit). Any fixed point will
+/// do, but keeping it recent keeps the scores small.
+const HOT_EPOCH: i64 = 1_500_422_400;
+/// Seconds over which a comment must gain ten times the votes to keep up with newer ones.
+const HOT_DECAY: f64 = 45_000.0;
+
+/// Lower bound of the Wilson score confidence interval for the fraction of positive votes.
+/// This favours comments which are well liked by many readers, so a comment with 10 likes
+/// ranks above one with 101 likes and 100 dislikes, which in turn ranks above one with a
+/// single like, since a single vote says little about how well liked a comment is.
+pub fn best(likes: i32, dislikes: i32) -> f64 {
+    let (likes, dislikes) = (likes.max(0), dislikes.max(0));
+    if likes + dislikes == 0 {
+        return 0.0;
+    }
+    let n = f64::from(likes + dislikes);
+    let z2 = CONFIDENCE_Z * CONFIDENCE_Z;
+    let p = f64::from(likes) / n;
+    (p + z2 / (2.0 * n) - CONFIDENCE_Z * ((p * (1.0 - p) + z2 / (4.0 * n)) / n).sqrt())
+        / (1.0 + z2 / n)
+}
+
+/// Ranks comments with many, evenly split, votes highest. Comments with only likes or
+/// only dislikes are not controversial at all.
+pub fn controversial(likes: i32, dislikes: i32) -> f64 {
+    if likes <= 0 || dislikes <= 0 {
+        return 0.0;
+    }
+    let magnitude = f64::from(likes + dislikes);
+    let balance = f64::from(likes.min(dislikes)) / f64::from(likes.max(dislikes));
+    magnitude.powf(balance)
+}
+
+/// Ranks comments by their total votes, with newer comments given a boost that older
+/// comments must make up for with ten times the votes every `HOT_DECAY` seconds.
+pub fn hot(likes: i32, dislikes: i32, created: NaiveDateTime) -> f64 {
+    let votes = likes - dislikes;
+    let order = f64::from(votes.abs().max(1)).log10();
+    let sign = f64::from(votes.signum());
+    let seconds = (created.timestamp() - HOT_EPOCH) as f64;
+    sign * order + seconds / HOT_DECAY
+}
@@ -0,0 +1,145 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel::sqlite::SqliteConnection;
+
+use cipher::Cipher;
+use crypto::digest::Digest;
+use crypto::sha2::Sha224;
+use errors::*;
+use rand::distributions::Alphanumeric;
+use rand::{OsRng, Rng};
+use schema::emails;
+
+#[derive(Insertable, Debug)]
+#[table_name = "emails"]
+/// Insertable reference to the emails table.
+struct NewEmail<'e> {
+    /// Blind index of the email address, so the address itself is never stored here.
+    email: &'e str,
+    /// Hash of the token kept by the browser the verification was requested from.
+    browser: &'e str,
+    /// Token sent to the address which verifies it.
+    token: Option<&'e str>,
+    /// If the address has been verified.
+    verified: bool,
+    /// Timestamp of creation.
+    created: NaiveDateTime,
+}
+
+/// Emails are matched case insensitively, without surrounding whitespace.
+pub fn normalise(address: &str) -> String {
+    address.trim().to_lowercase()
+}
+
+/// Checks if an email address has been verified by its owner from the browser which keeps
+/// the token `browser`. Only comments sent from such a browser may skip verification, since
+/// anyone can type in the address of a verified commentor.
+pub fn is_verified(
+    conn: &SqliteConnection,
+    cipher: &Cipher,
+    address: &str,
+    browser: Option<&str>,
+) -> Result<bool> {
+    let browser = match browser {
+        Some(token) if !token.is_empty() => browser_identity(token),
+        _ => return Ok(false),
+    };
+    let found = emails::table
+        .filter(
+            emails::email
+                .eq(cipher.index(address))
+                .and(emails::browser.eq(browser))
+                .and(emails::verified.eq(true)),
+        )
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(found > 0)
+}
+
+/// Returns a verification token for an email address, to be verified from the browser which
+/// keeps the token `browser`. If one has already been sent to this address for this browser
+/// it is reused, so earlier verification links stay valid.
+pub fn request_verification(
+    conn: &SqliteConnection,
+    cipher: &Cipher,
+    address: &str,
+    browser: &str,
+) -> Result<String> {
+    let index = cipher.index(address);
+    let browser = browser_identity(browser);
+    let existing = emails::table
+        .select(emails::token)
+        .filter(emails::email.eq(&index).and(emails::browser.eq(&browser)))
+        .first::<Option<String>>(conn)
+        .optional()
+        .chain_err(|| ErrorKind::DBRead)?;
+    if let Some(Some(token)) = existing {
+        return Ok(token);
+    }
+
+    let token = new_token()?;
+    let entry = NewEmail {
+        email: &index,
+        browser: &browser,
+        token: Some(&token),
+        verified: false,
+        created: Utc::now().naive_utc(),
+    };
+    diesel::replace_into(emails::table)
+        .values(&entry)
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBInsert)?;
+    Ok(token)
+}
+
+/// Marks the email address the `token` was sent to as verified from the browser it was
+/// requested from, and publishes the comments from that address which were sent from that
+/// browser and were waiting on verification. Returns the number of comments published.
+pub fn verify(conn: &SqliteConnection, token: &str) -> Result<usize> {
+    let (index, browser) = emails::table
+        .select((emails::email, emails::browser))
+        .filter(emails::token.eq(token))
+        .first::<(String, String)>(conn)
+        .optional()
+        .chain_err(|| ErrorKind::DBRead)?
+        .ok_or_else(|| Error::from(ErrorKind::InvalidToken))?;
+
+    diesel::update(emails::table.filter(emails::token.eq(token)))
+        .set((
+            emails::verified.eq(true),
+            emails::token.eq(None::<String>),
+        ))
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    //Comments held for verification are in mode 4. Others may have used this address from
+    //their own browser, and their comments stay held
+    sql_query(
+        "UPDATE comments SET mode = 0, browser = NULL \
+         WHERE mode = 4 AND email_index = ? AND browser = ?",
+    ).bind::<Text, _>(&index)
+        .bind::<Text, _>(&browser)
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBRead)
+}
+
+/// Identifies a browser by a hash of the token it keeps, so that tokens can't be recovered
+/// from the emails table.
+pub fn browser_identity(token: &str) -> String {
+    let mut hasher = Sha224::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+/// Generates a random token, either to send in a verification link or for a browser to keep.
+pub fn new_token() -> Result<String> {
+    Ok(OsRng::new()
+        .chain_err(|| ErrorKind::Rand)?
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .collect())
+}
@@ -0,0 +1,119 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use data::FlagReport;
+use errors::*;
+use models::comments::Comment;
+use models::privacy::hmac_address;
+use schema::{comments, flags, threads};
+
+#[derive(Insertable, Debug)]
+#[table_name = "flags"]
+/// Insertable reference to the flags table.
+struct NewFlag<'f> {
+    /// Reference to the flagged comment.
+    cid: i32,
+    /// Salted HMAC of the remote IP of the reader who flagged the comment.
+    remote_addr: &'f str,
+    /// Reason given by the reader.
+    reason: &'f str,
+    /// Timestamp of creation.
+    created: NaiveDateTime,
+}
+
+/// Stores a report against a live comment. Each IP address may only flag a given comment once,
+/// so the address is always kept, as an HMAC using `salt` whether or not `hash_ips` is set.
+/// Returns the total number of times this comment has now been flagged.
+pub fn insert<'f>(
+    conn: &SqliteConnection,
+    cid: i32,
+    ip_addr: &'f str,
+    salt: &'f str,
+    reason: &'f str,
+) -> Result<i64> {
+    let live = comments::table
+        .filter(comments::id.eq(cid).and(comments::mode.eq(0)))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    if live == 0 {
+        return Err(ErrorKind::NoComment(cid).into());
+    }
+
+    let hashed_addr = hmac_address(salt, ip_addr);
+    //Flags made before addresses were hashed may still hold the address itself
+    let reported = flags::table
+        .filter(flags::cid.eq(cid))
+        .filter(flags::remote_addr.eq_any(vec![ip_addr, hashed_addr.as_str()]))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    if reported > 0 {
+        return Err(ErrorKind::AlreadyFlagged.into());
+    }
+
+    let flag = NewFlag {
+        cid,
+        remote_addr: &hashed_addr,
+        reason,
+        created: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(flags::table)
+        .values(&flag)
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBInsert)?;
+
+    count(conn, cid)
+}
+
+/// Returns the number of times a comment has been flagged.
+pub fn count(conn: &SqliteConnection, cid: i32) -> Result<i64> {
+    let flag_count = flags::table
+        .filter(flags::cid.eq(cid))
+        .count()
+        .first(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(flag_count)
+}
+
+/// Moves a comment which has been flagged `flag_count` times into moderation once it reaches
+/// `limit` flags. A `limit` of 0 never moves comments. Returns true if the comment was moved.
+pub fn enforce_limit(
+    conn: &SqliteConnection,
+    cid: i32,
+    flag_count: i64,
+    limit: u32,
+) -> Result<bool> {
+    if limit > 0 && flag_count >= i64::from(limit) {
+        Comment::moderate(conn, cid)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Collects the details of a flagged comment needed to notify the admin.
+pub fn report(
+    conn: &SqliteConnection,
+    cid: i32,
+    reason: &str,
+    moderated: bool,
+) -> Result<FlagReport> {
+    let (text, path, title) = comments::table
+        .inner_join(threads::table)
+        .select((comments::text, threads::uri, threads::title))
+        .filter(comments::id.eq(cid))
+        .first::<(String, String, Option<String>)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    Ok(FlagReport {
+        id: cid,
+        text,
+        path,
+        title: title.unwrap_or_default(),
+        reason: reason.to_string(),
+        count: count(conn, cid)?,
+        moderated,
+    })
+}
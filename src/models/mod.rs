@@ -1,6 +1,26 @@
+/// Audit log of deleted and restored comments.
+pub mod audit;
 /// Comments table.
 pub mod comments;
+/// Emails table.
+pub mod emails;
+/// Flags table.
+pub mod flags;
 /// Preferences table.
 pub mod preferences;
+/// Export and erasure of the personal data of commentors.
+pub mod privacy;
+/// Comment and thread reactions tables.
+pub mod reactions;
+/// Comment revisions table.
+pub mod revisions;
+/// Full text search of comments.
+pub mod search;
 /// Threads table.
 pub mod threads;
+/// Deleted comments which can still be restored.
+pub mod trash;
+/// Trusted commentors table.
+pub mod trusted;
+/// Votes table.
+pub mod votes;
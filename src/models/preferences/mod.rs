@@ -2,15 +2,16 @@ use diesel;
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 
+use cipher::Cipher;
 use errors::*;
 use rand::distributions::Alphanumeric;
 use rand::{OsRng, Rng};
 use schema::preferences;
 
 #[table_name = "preferences"]
-#[derive(Queryable, Identifiable)]
+#[derive(Queryable, Identifiable, Insertable)]
 #[primary_key(key)]
-/// Queryable, Identifiable reference to the preferences table.
+/// Queryable, Identifiable, Insertable reference to the preferences table.
 pub struct Preference {
     /// Key
     pub key: String,
@@ -48,6 +49,52 @@ impl Preference {
             Err(ErrorKind::NoSession.into())
         }
     }
+
+    /// Stores the SMTP password of the site `site_name`, encrypted, in place of any earlier one.
+    pub fn set_smtp_password(
+        conn: &SqliteConnection,
+        cipher: &Cipher,
+        site_name: &str,
+        password: &str,
+    ) -> Result<()> {
+        let entry = Preference {
+            key: smtp_password_key(site_name),
+            value: cipher.encrypt(password)?,
+        };
+        diesel::replace_into(preferences::table)
+            .values(&entry)
+            .execute(conn)
+            .chain_err(|| ErrorKind::DBInsert)?;
+        Ok(())
+    }
+
+    /// Returns the SMTP password of the site `site_name` stored by `set_smtp_password`, decrypted.
+    /// Only to be called when a notification is about to be sent.
+    pub fn get_smtp_password(
+        conn: &SqliteConnection,
+        cipher: &Cipher,
+        site_name: &str,
+    ) -> Result<Option<String>> {
+        let stored = preferences::table
+            .select(preferences::value)
+            .filter(preferences::key.eq(smtp_password_key(site_name)))
+            .first::<String>(conn)
+            .optional()
+            .chain_err(|| ErrorKind::DBRead)?;
+        match stored {
+            Some(sealed) => Ok(Some(cipher.decrypt(&sealed)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Key under which the SMTP password of the site `site_name` is stored.
+fn smtp_password_key(site_name: &str) -> String {
+    if site_name.is_empty() {
+        "smtp-password".to_string()
+    } else {
+        format!("smtp-password:{}", site_name)
+    }
 }
 
 /// Generates a random hash used as a session ID.
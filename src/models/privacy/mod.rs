@@ -0,0 +1,558 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::BTreeSet;
+
+use cipher::{is_sealed, Cipher, SEALED};
+use errors::*;
+use models::audit::Snapshot;
+use models::emails;
+use schema::emails as email_table;
+use schema::{audit_log, comment_revisions, comment_trash, comments, flags, threads, trusted};
+
+/// The person whose data is requested, identified by the email address they gave or by their
+/// commentor identifier hash.
+#[derive(Debug)]
+pub enum Subject {
+    /// Email address, matched case insensitively.
+    Email(String),
+    /// Commentor identifier hash.
+    Hash(String),
+}
+
+impl Subject {
+    /// Reads a subject given on the command line: anything containing an `@` is an email
+    /// address, and anything else an identifier hash. Returns `None` if `value` is blank, as
+    /// erased comments are left with a blank identifier.
+    pub fn parse(value: &str) -> Option<Subject> {
+        let value = value.trim();
+        if value.is_empty() {
+            None
+        } else if value.contains('@') {
+            Some(Subject::Email(emails::normalise(value)))
+        } else {
+            Some(Subject::Hash(value.to_string()))
+        }
+    }
+
+    /// Checks if a snapshot of a comment belongs to this subject.
+    fn owns(&self, snapshot: &Snapshot, cipher: &Cipher) -> bool {
+        match *self {
+            Subject::Email(ref address) => {
+                snapshot.email_index.as_ref() == Some(&cipher.index(address))
+            }
+            Subject::Hash(ref hash) => snapshot.hash == *hash,
+        }
+    }
+}
+
+#[derive(Queryable, Serialize, Debug)]
+/// A previous version of one of the subject's comments.
+pub struct ExportedRevision {
+    /// Primary key.
+    id: i32,
+    /// Reference to the edited comment.
+    cid: i32,
+    /// Text of the comment at the time.
+    text: String,
+    /// Commentors author at the time.
+    author: Option<String>,
+    /// Commentors email address at the time.
+    email: Option<String>,
+    /// Commentors website at the time.
+    website: Option<String>,
+    /// Commentors indentifier at the time.
+    hash: String,
+    /// Timestamp of when this revision was written.
+    created: NaiveDateTime,
+    /// Timestamp of when this revision was replaced.
+    replaced: NaiveDateTime,
+}
+
+#[derive(Serialize, Debug)]
+/// Everything stored about a subject.
+pub struct PersonalData {
+    /// Every comment the subject has left, on any site.
+    comments: Vec<Snapshot>,
+    /// Previous versions of those comments.
+    revisions: Vec<ExportedRevision>,
+    /// Deleted comments of the subject which are still in the trash.
+    trash: Vec<Snapshot>,
+    /// If the subject's email address has been verified.
+    email_verified: Option<bool>,
+    /// If the subject is trusted to comment without moderation.
+    trusted: bool,
+}
+
+#[derive(AsChangeset, Debug)]
+#[table_name = "comments"]
+#[changeset_options(treat_none_as_null = "true")]
+/// Changes required to remove the personal data from a comment, whilst leaving it in place.
+struct ModeErase {
+    /// Remote IP.
+    remote_addr: Option<String>,
+    /// Commentors author.
+    author: Option<String>,
+    /// Commentors email address.
+    email: Option<String>,
+    /// Blind index of the commentors email address.
+    email_index: Option<String>,
+    /// Commentors website.
+    website: Option<String>,
+    /// Commentors indentifier.
+    hash: String,
+}
+
+/// Loads every comment of the `subject`.
+fn find_comments(
+    conn: &SqliteConnection,
+    cipher: &Cipher,
+    subject: &Subject,
+) -> QueryResult<Vec<Snapshot>> {
+    let query = comments::table
+        .inner_join(threads::table)
+        .select((
+            comments::id,
+            threads::site,
+            threads::uri,
+            threads::title,
+            comments::parent,
+            comments::created,
+            comments::modified,
+            comments::mode,
+            comments::remote_addr,
+            comments::text,
+            comments::author,
+            comments::email,
+            comments::email_index,
+            comments::website,
+            comments::hash,
+            comments::likes,
+            comments::dislikes,
+        ))
+        .order(comments::id.asc())
+        .into_boxed();
+    let query = match *subject {
+        Subject::Email(ref address) => {
+            query.filter(comments::email_index.eq(cipher.index(address)))
+        }
+        Subject::Hash(ref hash) => query.filter(comments::hash.eq(hash)),
+    };
+    query.load::<Snapshot>(conn)
+}
+
+/// Loads the previous versions of the comments `cids`, along with any other previous version
+/// which belongs to the `subject`.
+fn find_revisions(
+    conn: &SqliteConnection,
+    cipher: &Cipher,
+    subject: &Subject,
+    cids: &[i32],
+) -> QueryResult<Vec<ExportedRevision>> {
+    let query = comment_revisions::table
+        .select((
+            comment_revisions::id,
+            comment_revisions::cid,
+            comment_revisions::text,
+            comment_revisions::author,
+            comment_revisions::email,
+            comment_revisions::website,
+            comment_revisions::hash,
+            comment_revisions::created,
+            comment_revisions::replaced,
+        ))
+        .order(comment_revisions::id.asc())
+        .into_boxed();
+    let query = match *subject {
+        Subject::Email(ref address) => query.filter(
+            comment_revisions::cid
+                .eq_any(cids)
+                .or(comment_revisions::email_index.eq(cipher.index(address))),
+        ),
+        Subject::Hash(ref hash) => query.filter(
+            comment_revisions::cid
+                .eq_any(cids)
+                .or(comment_revisions::hash.eq(hash)),
+        ),
+    };
+    query.load::<ExportedRevision>(conn)
+}
+
+/// Decrypts the email address of an exported comment or revision.
+fn unseal(cipher: &Cipher, email: &mut Option<String>) -> Result<()> {
+    if let Some(ref mut address) = *email {
+        let plain = cipher.decrypt(address)?;
+        *address = plain;
+    }
+    Ok(())
+}
+
+/// Gathers everything stored about the `subject`, so it can be handed over to them.
+/// This is the only time email addresses are decrypted outside of sending notifications.
+pub fn export(
+    conn: &SqliteConnection,
+    cipher: &Cipher,
+    subject: &Subject,
+) -> Result<PersonalData> {
+    let mut comments = find_comments(conn, cipher, subject).chain_err(|| ErrorKind::DBRead)?;
+    let cids: Vec<i32> = comments.iter().map(|c| c.id).collect();
+    let mut revisions =
+        find_revisions(conn, cipher, subject, &cids).chain_err(|| ErrorKind::DBRead)?;
+    for comment in &mut comments {
+        unseal(cipher, &mut comment.email)?;
+    }
+    for revision in &mut revisions {
+        unseal(cipher, &mut revision.email)?;
+    }
+
+    let stored_trash = comment_trash::table
+        .select(comment_trash::snapshot)
+        .load::<String>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    let mut trash = Vec::new();
+    for stored in stored_trash {
+        let mut snapshot = Snapshot::decode(&stored)?;
+        if subject.owns(&snapshot, cipher) {
+            unseal(cipher, &mut snapshot.email)?;
+            trash.push(snapshot);
+        }
+    }
+
+    let email_verified = match *subject {
+        Subject::Email(ref address) => {
+            //The address is stored once for each browser it was verified from
+            let browsers = email_table::table
+                .select(email_table::verified)
+                .filter(email_table::email.eq(cipher.index(address)))
+                .load::<bool>(conn)
+                .chain_err(|| ErrorKind::DBRead)?;
+            if browsers.is_empty() {
+                None
+            } else {
+                Some(browsers.contains(&true))
+            }
+        }
+        Subject::Hash(_) => None,
+    };
+    let hashes: Vec<String> = comments.iter().map(|c| c.hash.to_owned()).collect();
+    let trusted_count = trusted::table
+        .filter(trusted::hash.eq_any(&hashes))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    Ok(PersonalData {
+        comments,
+        revisions,
+        trash,
+        email_verified,
+        trusted: trusted_count > 0,
+    })
+}
+
+/// Removes the personal details from a snapshot of a comment.
+fn blank(snapshot: &mut Snapshot) {
+    snapshot.remote_addr = None;
+    snapshot.author = None;
+    snapshot.email = None;
+    snapshot.email_index = None;
+    snapshot.website = None;
+    snapshot.hash = String::new();
+}
+
+/// Erases the personal data of the `subject`: their name, email address, website, IP address
+/// and identifier are removed from their comments, previous versions of them, the trash and
+/// the audit log. The comments themselves stay in place, so replies to them are kept intact.
+/// Returns the number of comments which were erased.
+pub fn erase(conn: &SqliteConnection, cipher: &Cipher, subject: &Subject) -> Result<usize> {
+    let comments = find_comments(conn, cipher, subject).chain_err(|| ErrorKind::DBRead)?;
+    let cids: Vec<i32> = comments.iter().map(|c| c.id).collect();
+    let hashes: BTreeSet<String> = comments.iter().map(|c| c.hash.to_owned()).collect();
+    let hashes: Vec<String> = hashes.into_iter().collect();
+
+    //Snapshots are stored encoded, so they are checked one at a time
+    let mut trash = Vec::new();
+    let stored_trash = comment_trash::table
+        .select((comment_trash::id, comment_trash::snapshot))
+        .load::<(i32, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    for (id, stored) in stored_trash {
+        let mut snapshot = Snapshot::decode(&stored)?;
+        if subject.owns(&snapshot, cipher) {
+            blank(&mut snapshot);
+            trash.push((id, snapshot));
+        }
+    }
+    let mut audited = Vec::new();
+    let stored_audit = audit_log::table
+        .select((audit_log::id, audit_log::actor, audit_log::snapshot))
+        .load::<(i32, String, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    for (id, actor, stored) in stored_audit {
+        let mut snapshot = Snapshot::decode(&stored)?;
+        if subject.owns(&snapshot, cipher) {
+            //Commentors who deleted their own comment are recorded by their identifier
+            let actor = if actor == snapshot.hash {
+                String::new()
+            } else {
+                actor
+            };
+            blank(&mut snapshot);
+            audited.push((id, actor, snapshot));
+        }
+    }
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(comments::table.filter(comments::id.eq_any(&cids)))
+            .set(&ModeErase {
+                remote_addr: None,
+                author: None,
+                email: None,
+                email_index: None,
+                website: None,
+                hash: String::new(),
+            })
+            .execute(conn)?;
+
+        let revisions: Vec<i32> = find_revisions(conn, cipher, subject, &cids)?
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        diesel::update(comment_revisions::table.filter(comment_revisions::id.eq_any(&revisions)))
+            .set((
+                comment_revisions::author.eq(None::<String>),
+                comment_revisions::email.eq(None::<String>),
+                comment_revisions::email_index.eq(None::<String>),
+                comment_revisions::website.eq(None::<String>),
+                comment_revisions::hash.eq(""),
+            ))
+            .execute(conn)?;
+
+        for &(id, ref snapshot) in &trash {
+            diesel::update(comment_trash::table.filter(comment_trash::id.eq(id)))
+                .set(comment_trash::snapshot.eq(snapshot.encode()?))
+                .execute(conn)?;
+        }
+        for &(id, ref actor, ref snapshot) in &audited {
+            diesel::update(audit_log::table.filter(audit_log::id.eq(id)))
+                .set((
+                    audit_log::actor.eq(actor),
+                    audit_log::snapshot.eq(snapshot.encode()?),
+                ))
+                .execute(conn)?;
+        }
+
+        if let Subject::Email(ref address) = *subject {
+            let index = cipher.index(address);
+            diesel::delete(email_table::table.filter(email_table::email.eq(index)))
+                .execute(conn)?;
+        }
+        diesel::delete(trusted::table.filter(trusted::hash.eq_any(&hashes))).execute(conn)?;
+        Ok(())
+    }).chain_err(|| ErrorKind::DBInsert)?;
+
+    Ok(cids.len())
+}
+
+/// Encrypts the email address held in a snapshot taken before addresses were encrypted.
+/// Returns false if there was nothing to encrypt.
+fn seal_snapshot(cipher: &Cipher, snapshot: &mut Snapshot) -> Result<bool> {
+    let sealed = match snapshot.email {
+        Some(ref address) if !is_sealed(address) => cipher.encrypt(address)?,
+        _ => return Ok(false),
+    };
+    snapshot.email_index = snapshot.email.as_ref().map(|address| cipher.index(address));
+    snapshot.email = Some(sealed);
+    Ok(true)
+}
+
+/// Encrypts every email address stored before encryption was introduced: in comments,
+/// previous versions of them, the trash and the audit log. Verified addresses are replaced
+/// by their blind index. Addresses which are already encrypted are left alone, so this is
+/// run on every start. Returns the number of addresses encrypted.
+pub fn seal_emails(conn: &SqliteConnection, cipher: &Cipher) -> Result<usize> {
+    let sealed = format!("{}%", SEALED);
+    let plain_comments = comments::table
+        .select((comments::id, comments::email))
+        .filter(comments::email.not_like(&sealed))
+        .load::<(i32, Option<String>)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    let mut sealed_comments = Vec::new();
+    for (id, email) in plain_comments {
+        if let Some(address) = email {
+            sealed_comments.push((id, cipher.encrypt(&address)?, cipher.index(&address)));
+        }
+    }
+    let plain_revisions = comment_revisions::table
+        .select((comment_revisions::id, comment_revisions::email))
+        .filter(comment_revisions::email.not_like(&sealed))
+        .load::<(i32, Option<String>)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    let mut sealed_revisions = Vec::new();
+    for (id, email) in plain_revisions {
+        if let Some(address) = email {
+            sealed_revisions.push((id, cipher.encrypt(&address)?, cipher.index(&address)));
+        }
+    }
+
+    //Snapshots are stored encoded, so only those which hold an address are checked
+    let holds_email = "%\"email\":\"%";
+    let mut trash = Vec::new();
+    let stored_trash = comment_trash::table
+        .select((comment_trash::id, comment_trash::snapshot))
+        .filter(comment_trash::snapshot.like(holds_email))
+        .load::<(i32, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    for (id, stored) in stored_trash {
+        let mut snapshot = Snapshot::decode(&stored)?;
+        if seal_snapshot(cipher, &mut snapshot)? {
+            trash.push((id, snapshot));
+        }
+    }
+    let mut audited = Vec::new();
+    let stored_audit = audit_log::table
+        .select((audit_log::id, audit_log::snapshot))
+        .filter(audit_log::snapshot.like(holds_email))
+        .load::<(i32, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    for (id, stored) in stored_audit {
+        let mut snapshot = Snapshot::decode(&stored)?;
+        if seal_snapshot(cipher, &mut snapshot)? {
+            audited.push((id, snapshot));
+        }
+    }
+    //Blind indexes are hex encoded, so never hold an @
+    let verified = email_table::table
+        .select(email_table::email)
+        .filter(email_table::email.like("%@%"))
+        .load::<String>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        for &(id, ref email, ref index) in &sealed_comments {
+            diesel::update(comments::table.filter(comments::id.eq(id)))
+                .set((
+                    comments::email.eq(email),
+                    comments::email_index.eq(index),
+                ))
+                .execute(conn)?;
+        }
+        for &(id, ref email, ref index) in &sealed_revisions {
+            diesel::update(comment_revisions::table.filter(comment_revisions::id.eq(id)))
+                .set((
+                    comment_revisions::email.eq(email),
+                    comment_revisions::email_index.eq(index),
+                ))
+                .execute(conn)?;
+        }
+        for &(id, ref snapshot) in &trash {
+            diesel::update(comment_trash::table.filter(comment_trash::id.eq(id)))
+                .set(comment_trash::snapshot.eq(snapshot.encode()?))
+                .execute(conn)?;
+        }
+        for &(id, ref snapshot) in &audited {
+            diesel::update(audit_log::table.filter(audit_log::id.eq(id)))
+                .set(audit_log::snapshot.eq(snapshot.encode()?))
+                .execute(conn)?;
+        }
+        for address in &verified {
+            diesel::update(email_table::table.filter(email_table::email.eq(address)))
+                .set(email_table::email.eq(cipher.index(address)))
+                .execute(conn)?;
+        }
+        Ok(())
+    }).chain_err(|| ErrorKind::DBInsert)?;
+
+    Ok(sealed_comments.len() + sealed_revisions.len() + trash.len() + audited.len())
+}
+
+/// Computes a salted HMAC of an IP address, so the address itself need not be stored.
+pub fn hmac_address(salt: &str, ip_addr: &str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), salt.as_bytes());
+    mac.input(ip_addr.as_bytes());
+    mac.result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks if a stored address is an IP address itself, rather than an HMAC of one.
+fn is_plain_address(stored: &str) -> bool {
+    stored.contains('.') || stored.contains(':')
+}
+
+/// Removes the IP addresses stored with comments posted more than `days` ago, including from
+/// snapshots in the trash and audit log. Flags are stored with an HMAC of the address, but any
+/// made before then which still hold the address itself are hashed too, using `salt`.
+/// Returns the number of comments whose address was removed.
+pub fn forget_addresses(conn: &SqliteConnection, days: u32, salt: &str) -> Result<usize> {
+    let cutoff = Utc::now().naive_utc() - Duration::days(i64::from(days));
+    //Snapshots are stored encoded, so only those which still hold an address are checked
+    let holds_address = "%\"remote_addr\":\"%";
+
+    let mut trash = Vec::new();
+    let stored_trash = comment_trash::table
+        .select((comment_trash::id, comment_trash::snapshot))
+        .filter(comment_trash::snapshot.like(holds_address))
+        .load::<(i32, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    for (id, stored) in stored_trash {
+        let mut snapshot = Snapshot::decode(&stored)?;
+        if snapshot.created <= cutoff {
+            snapshot.remote_addr = None;
+            trash.push((id, snapshot));
+        }
+    }
+    let mut audited = Vec::new();
+    let stored_audit = audit_log::table
+        .select((audit_log::id, audit_log::snapshot))
+        .filter(audit_log::snapshot.like(holds_address))
+        .load::<(i32, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    for (id, stored) in stored_audit {
+        let mut snapshot = Snapshot::decode(&stored)?;
+        if snapshot.created <= cutoff {
+            snapshot.remote_addr = None;
+            audited.push((id, snapshot));
+        }
+    }
+    let old_flags: Vec<(i32, String)> = flags::table
+        .select((flags::id, flags::remote_addr))
+        .filter(flags::created.le(cutoff))
+        .load::<(i32, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?
+        .into_iter()
+        .filter(|&(_, ref stored)| is_plain_address(stored))
+        .collect();
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let forgotten = diesel::update(
+            comments::table
+                .filter(comments::remote_addr.is_not_null())
+                .filter(comments::created.le(cutoff)),
+        ).set(comments::remote_addr.eq(None::<String>))
+            .execute(conn)?;
+
+        for &(id, ref snapshot) in &trash {
+            diesel::update(comment_trash::table.filter(comment_trash::id.eq(id)))
+                .set(comment_trash::snapshot.eq(snapshot.encode()?))
+                .execute(conn)?;
+        }
+        for &(id, ref snapshot) in &audited {
+            diesel::update(audit_log::table.filter(audit_log::id.eq(id)))
+                .set(audit_log::snapshot.eq(snapshot.encode()?))
+                .execute(conn)?;
+        }
+        for &(id, ref stored) in &old_flags {
+            diesel::update(flags::table.filter(flags::id.eq(id)))
+                .set(flags::remote_addr.eq(hmac_address(salt, stored)))
+                .execute(conn)?;
+        }
+        Ok(forgotten)
+    }).chain_err(|| ErrorKind::DBInsert)
+}
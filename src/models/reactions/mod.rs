@@ -0,0 +1,185 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::{BTreeMap, HashMap};
+
+use errors::*;
+use schema::{comment_reactions, comments, thread_reactions};
+
+/// Number of readers who left each reaction.
+pub type Tally = BTreeMap<String, i64>;
+
+#[derive(Insertable, Debug)]
+#[table_name = "comment_reactions"]
+/// Insertable reference to the comment_reactions table.
+struct NewCommentReaction<'r> {
+    /// Reference to the comment.
+    cid: i32,
+    /// Salted hash identifying the reader.
+    voter: &'r str,
+    /// The reaction left.
+    reaction: &'r str,
+    /// Timestamp of the reaction.
+    created: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "thread_reactions"]
+/// Insertable reference to the thread_reactions table.
+struct NewThreadReaction<'r> {
+    /// Reference to the thread.
+    tid: i32,
+    /// Salted hash identifying the reader.
+    voter: &'r str,
+    /// The reaction left.
+    reaction: &'r str,
+    /// Timestamp of the reaction.
+    created: NaiveDateTime,
+}
+
+/// Refuses reactions on comments which are not live, so hidden comments aren't given away.
+fn check_live(conn: &SqliteConnection, cid: i32) -> Result<()> {
+    let live = comments::table
+        .filter(comments::id.eq(cid).and(comments::mode.eq(0)))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    if live == 0 {
+        return Err(ErrorKind::NoComment(cid).into());
+    }
+    Ok(())
+}
+
+/// Leaves a reaction on a live comment. Each reader may leave each reaction once.
+/// Returns the updated reactions of the comment.
+pub fn add_to_comment(
+    conn: &SqliteConnection,
+    cid: i32,
+    voter: &str,
+    reaction: &str,
+) -> Result<Tally> {
+    check_live(conn, cid)?;
+    let new_reaction = NewCommentReaction {
+        cid,
+        voter,
+        reaction,
+        created: Utc::now().naive_utc(),
+    };
+    diesel::replace_into(comment_reactions::table)
+        .values(&new_reaction)
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBInsert)?;
+    for_comment(conn, cid)
+}
+
+/// Removes a reaction a reader left on a live comment.
+/// Returns the updated reactions of the comment.
+pub fn remove_from_comment(
+    conn: &SqliteConnection,
+    cid: i32,
+    voter: &str,
+    reaction: &str,
+) -> Result<Tally> {
+    check_live(conn, cid)?;
+    diesel::delete(
+        comment_reactions::table.filter(
+            comment_reactions::cid
+                .eq(cid)
+                .and(comment_reactions::voter.eq(voter))
+                .and(comment_reactions::reaction.eq(reaction)),
+        ),
+    ).execute(conn)
+    .chain_err(|| ErrorKind::DBRead)?;
+    for_comment(conn, cid)
+}
+
+/// Leaves a reaction on a thread. Each reader may leave each reaction once.
+/// Returns the updated reactions of the thread.
+pub fn add_to_thread(
+    conn: &SqliteConnection,
+    tid: i32,
+    voter: &str,
+    reaction: &str,
+) -> Result<Tally> {
+    let new_reaction = NewThreadReaction {
+        tid,
+        voter,
+        reaction,
+        created: Utc::now().naive_utc(),
+    };
+    diesel::replace_into(thread_reactions::table)
+        .values(&new_reaction)
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBInsert)?;
+    for_thread(conn, tid)
+}
+
+/// Removes a reaction a reader left on a thread.
+/// Returns the updated reactions of the thread.
+pub fn remove_from_thread(
+    conn: &SqliteConnection,
+    tid: i32,
+    voter: &str,
+    reaction: &str,
+) -> Result<Tally> {
+    diesel::delete(
+        thread_reactions::table.filter(
+            thread_reactions::tid
+                .eq(tid)
+                .and(thread_reactions::voter.eq(voter))
+                .and(thread_reactions::reaction.eq(reaction)),
+        ),
+    ).execute(conn)
+    .chain_err(|| ErrorKind::DBRead)?;
+    for_thread(conn, tid)
+}
+
+/// Returns the reactions left on a comment.
+pub fn for_comment(conn: &SqliteConnection, cid: i32) -> Result<Tally> {
+    let reactions = comment_reactions::table
+        .select(comment_reactions::reaction)
+        .filter(comment_reactions::cid.eq(cid))
+        .load::<String>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(tally(reactions))
+}
+
+/// Returns the reactions left on every comment in a thread, keyed by comment id.
+pub fn for_comments(conn: &SqliteConnection, tid: i32) -> Result<HashMap<i32, Tally>> {
+    let reactions = comment_reactions::table
+        .inner_join(comments::table)
+        .select((comment_reactions::cid, comment_reactions::reaction))
+        .filter(comments::tid.eq(tid))
+        .load::<(i32, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    let mut by_comment: HashMap<i32, Tally> = HashMap::new();
+    for (cid, reaction) in reactions {
+        *by_comment
+            .entry(cid)
+            .or_insert_with(Tally::new)
+            .entry(reaction)
+            .or_insert(0) += 1;
+    }
+    Ok(by_comment)
+}
+
+/// Returns the reactions left on a thread.
+pub fn for_thread(conn: &SqliteConnection, tid: i32) -> Result<Tally> {
+    let reactions = thread_reactions::table
+        .select(thread_reactions::reaction)
+        .filter(thread_reactions::tid.eq(tid))
+        .load::<String>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(tally(reactions))
+}
+
+/// Counts the number of times each reaction appears.
+fn tally(reactions: Vec<String>) -> Tally {
+    let mut counts = Tally::new();
+    for reaction in reactions {
+        *counts.entry(reaction).or_insert(0) += 1;
+    }
+    counts
+}
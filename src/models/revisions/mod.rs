@@ -0,0 +1,168 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use errors::*;
+use models::comments::get_author;
+use schema::{comment_revisions, comments};
+
+#[derive(Insertable, Debug)]
+#[table_name = "comment_revisions"]
+/// Insertable reference to the comment_revisions table.
+struct NewRevision {
+    /// Reference to the edited comment.
+    cid: i32,
+    /// Text of the comment at the time.
+    text: String,
+    /// Commentors author at the time.
+    author: Option<String>,
+    /// Commentors email address at the time, encrypted.
+    email: Option<String>,
+    /// Commentors website at the time.
+    website: Option<String>,
+    /// Commentors indentifier at the time.
+    hash: String,
+    /// Timestamp of when this revision was written.
+    created: NaiveDateTime,
+    /// Timestamp of when this revision was replaced.
+    replaced: NaiveDateTime,
+    /// Blind index of the commentors email address at the time.
+    email_index: Option<String>,
+}
+
+#[derive(Queryable, Debug)]
+/// Queryable reference to the comment_revisions table.
+struct StoredRevision {
+    /// Primary key.
+    id: i32,
+    /// Reference to the edited comment.
+    cid: i32,
+    /// Text of the comment at the time.
+    text: String,
+    /// Commentors author at the time.
+    author: Option<String>,
+    /// Commentors email address at the time, encrypted.
+    email: Option<String>,
+    /// Commentors website at the time.
+    website: Option<String>,
+    /// Commentors indentifier at the time.
+    hash: String,
+    /// Timestamp of when this revision was written.
+    created: NaiveDateTime,
+    /// Timestamp of when this revision was replaced.
+    replaced: NaiveDateTime,
+    /// Blind index of the commentors email address at the time.
+    email_index: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+/// A previous version of a comment.
+pub struct Revision {
+    /// Primary key, used to restore this revision.
+    id: i32,
+    /// Text of the comment at the time.
+    text: String,
+    /// Commentors author at the time.
+    author: Option<String>,
+    /// Commentors indentifier at the time.
+    hash: String,
+    /// Timestamp of when this revision was written.
+    created: DateTime<Utc>,
+    /// Timestamp of when this revision was replaced.
+    replaced: DateTime<Utc>,
+}
+
+/// Stores the current text and author details of the comment `cid` as a revision, so they
+/// can be replaced. Only to be called from within a transaction which replaces them.
+pub fn record(conn: &SqliteConnection, cid: i32) -> QueryResult<()> {
+    let (text, author, email, website, hash, created, modified, email_index) = comments::table
+        .select((
+            comments::text,
+            comments::author,
+            comments::email,
+            comments::website,
+            comments::hash,
+            comments::created,
+            comments::modified,
+            comments::email_index,
+        ))
+        .filter(comments::id.eq(cid))
+        .first::<(
+            String,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            String,
+            NaiveDateTime,
+            Option<NaiveDateTime>,
+            Option<String>,
+        )>(conn)?;
+
+    let revision = NewRevision {
+        cid,
+        text,
+        author,
+        email,
+        website,
+        hash,
+        created: modified.unwrap_or(created),
+        replaced: Utc::now().naive_utc(),
+        email_index,
+    };
+    diesel::insert_into(comment_revisions::table)
+        .values(&revision)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Returns every previous version of the comment `cid`, oldest first.
+pub fn list(conn: &SqliteConnection, cid: i32) -> Result<Vec<Revision>> {
+    let revisions = comment_revisions::table
+        .filter(comment_revisions::cid.eq(cid))
+        .order(comment_revisions::id.asc())
+        .load::<StoredRevision>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    Ok(revisions
+        .into_iter()
+        .map(|revision| Revision {
+            id: revision.id,
+            author: get_author(&revision.author, &revision.website),
+            text: revision.text,
+            hash: revision.hash,
+            created: DateTime::from_utc(revision.created, Utc),
+            replaced: DateTime::from_utc(revision.replaced, Utc),
+        })
+        .collect())
+}
+
+/// Rolls the comment `cid` back to its revision `id`. The version being replaced is kept
+/// as a revision too, so a roll back can itself be undone.
+pub fn restore(conn: &SqliteConnection, cid: i32, id: i32) -> Result<()> {
+    let revision = comment_revisions::table
+        .filter(comment_revisions::id.eq(id))
+        .first::<StoredRevision>(conn)
+        .optional()
+        .chain_err(|| ErrorKind::DBRead)?;
+    let revision = match revision {
+        Some(ref revision) if revision.cid == cid => revision,
+        _ => return Err(ErrorKind::NoRevision(id).into()),
+    };
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        record(conn, cid)?;
+        diesel::update(comments::table.filter(comments::id.eq(cid)))
+            .set((
+                comments::text.eq(&revision.text),
+                comments::author.eq(&revision.author),
+                comments::email.eq(&revision.email),
+                comments::email_index.eq(&revision.email_index),
+                comments::website.eq(&revision.website),
+                comments::hash.eq(&revision.hash),
+                comments::modified.eq(Some(Utc::now().naive_utc())),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }).chain_err(|| ErrorKind::DBInsert)
+}
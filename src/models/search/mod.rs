@@ -0,0 +1,154 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Text, Timestamp};
+use diesel::sqlite::SqliteConnection;
+
+use config::Site;
+use errors::*;
+use models::comments::get_author;
+use render::escape;
+
+/// Marks the start of a matched term in a snippet before it is escaped.
+const MATCH_START: char = '\u{2}';
+/// Marks the end of a matched term in a snippet before it is escaped.
+const MATCH_END: char = '\u{3}';
+
+#[derive(QueryableByName, Debug)]
+/// A comment found by a search, along with the post it was left on.
+struct Found {
+    /// Primary key.
+    #[sql_type = "Integer"]
+    id: i32,
+    /// Moderation mode of the comment.
+    #[sql_type = "Integer"]
+    mode: i32,
+    /// Commentors author if given.
+    #[sql_type = "Nullable<Text>"]
+    author: Option<String>,
+    /// Commentors website if given.
+    #[sql_type = "Nullable<Text>"]
+    website: Option<String>,
+    /// Commentors indentifier.
+    #[sql_type = "Text"]
+    hash: String,
+    /// Timestamp of creation.
+    #[sql_type = "Timestamp"]
+    created: NaiveDateTime,
+    /// The part of the comment which best matches the search.
+    #[sql_type = "Text"]
+    snippet: String,
+    /// URI of the post.
+    #[sql_type = "Text"]
+    uri: String,
+    /// Title of the post.
+    #[sql_type = "Nullable<Text>"]
+    title: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+/// A comment matching a search.
+pub struct SearchResult {
+    /// Primary key.
+    id: i32,
+    /// Moderation mode of the comment. Only shown to administrators.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<i32>,
+    /// Commentors author if given.
+    author: Option<String>,
+    /// Commentors indentifier.
+    hash: String,
+    /// Timestamp of creation.
+    created: DateTime<Utc>,
+    /// The part of the comment which best matches the search, as HTML with each matched term
+    /// wrapped in `<mark>`.
+    snippet: String,
+    /// Title of the post.
+    title: Option<String>,
+    /// URI of the post.
+    uri: String,
+    /// Link to the comment on the post's page.
+    permalink: String,
+}
+
+/// Quotes a word or phrase so that it is matched literally, rather than as query syntax.
+fn quote(phrase: &str) -> String {
+    format!("\"{}\"", phrase.replace('"', "\"\""))
+}
+
+/// Builds a full text query matching comments which contain every word of `terms` in their
+/// text, author or post title, and which were written by `author`. Returns `None` if there
+/// is nothing to search for.
+pub fn match_expression(terms: Option<&str>, author: Option<&str>) -> Option<String> {
+    let mut parts: Vec<String> = terms
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(quote)
+        .collect();
+    if let Some(author) = author.map(str::trim).filter(|a| !a.is_empty()) {
+        parts.push(format!("author : {}", quote(author)));
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" "))
+    }
+}
+
+/// Turns a snippet from the search index into HTML, highlighting the matched terms.
+fn highlight(snippet: &str) -> String {
+    escape(snippet)
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
+
+/// Returns up to `limit` comments on the `site` matching the full text query `expression`,
+/// best matches first. Only published comments are searched unless `include_hidden` is set,
+/// in which case comments awaiting moderation, shadow banned or unverified are included too.
+pub fn search(
+    conn: &SqliteConnection,
+    site: &Site,
+    expression: &str,
+    include_hidden: bool,
+    limit: i64,
+) -> Result<Vec<SearchResult>> {
+    let found = sql_query(format!(
+        "SELECT comments.id AS id, comments.mode AS mode, comments.author AS author,
+            comments.website AS website, comments.hash AS hash, comments.created AS created,
+            snippet(comment_search, 0, '{}', '{}', '…', 16) AS snippet,
+            threads.uri AS uri, threads.title AS title
+        FROM comment_search
+        INNER JOIN comments ON comments.id = comment_search.rowid
+        INNER JOIN threads ON threads.id = comments.tid
+        WHERE comment_search MATCH ? AND threads.site = ?
+            AND comments.mode <> 2 AND (comments.mode = 0 OR ?)
+        ORDER BY comment_search.rank
+        LIMIT ?",
+        MATCH_START, MATCH_END
+    )).bind::<Text, _>(expression)
+        .bind::<Text, _>(&site.name)
+        .bind::<Bool, _>(include_hidden)
+        .bind::<BigInt, _>(limit)
+        .load::<Found>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    let host = site.host.trim_right_matches('/');
+    Ok(found
+        .into_iter()
+        .map(|comment| SearchResult {
+            id: comment.id,
+            mode: if include_hidden {
+                Some(comment.mode)
+            } else {
+                None
+            },
+            author: get_author(&comment.author, &comment.website),
+            hash: comment.hash,
+            created: DateTime::from_utc(comment.created, Utc),
+            snippet: highlight(&comment.snippet),
+            permalink: format!("{}{}#comment-{}", host, comment.uri, comment.id),
+            title: comment.title,
+            uri: comment.uri,
+        })
+        .collect())
+}
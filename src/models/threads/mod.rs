@@ -1,26 +1,42 @@
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel;
+use diesel::dsl::min;
 use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Integer;
 use diesel::sqlite::SqliteConnection;
 
+use config::{Normalise, Site, Threads};
 use errors::*;
+use regex::Regex;
 use reqwest;
-use schema::threads;
+use schema::{comments, thread_aliases, thread_reactions, threads};
+use std::collections::{BTreeMap, HashMap};
+use verify::Verifier;
 
 #[derive(Serialize, Queryable, Debug)]
 /// Queryable reference to the threads table.
 pub struct Thread {
     /// Primary key
     pub id: i32,
+    /// Name of the site the thread belongs to
+    pub site: String,
     /// URI to the thread
     pub uri: String,
     /// Thread title
     pub title: Option<String>,
+    /// If the thread is closed to further activity
+    pub closed: bool,
+    /// If the thread was reopened after being closed automatically
+    pub reopened: bool,
 }
 
 #[derive(Insertable, Debug)]
 #[table_name = "threads"]
 /// Insertable reference to the threads table.
 struct NewThread<'t> {
+    /// Name of the site the thread belongs to.
+    site: &'t str,
     /// URI to the thread.
     uri: &'t str,
     /// Thread title.
@@ -29,21 +45,35 @@ struct NewThread<'t> {
 
 /// Returns a thread ID given creation details about it.
 /// If the thread exists, an ID is returned directly, otherwise an entry
-/// is created for it first
-pub fn gen_or_get_id(conn: &SqliteConnection, host: &str, title: &str, path: &str) -> Result<i32> {
-    match get_id(conn, path) {
-        //TODO: Maybe the id is the same, but the title has been updated.
-        Ok(id) => Ok(id), //Found an id, return it
+/// is created for it first. New threads are stored under their normalised `path`, and titled
+/// as `update_title` would. An existing thread is only retitled once a live comment has been
+/// left on it (see `update_title`).
+pub fn gen_or_get_id(
+    conn: &SqliteConnection,
+    site: &Site,
+    title: &str,
+    path: &str,
+    settings: &Threads,
+    verifier: &Verifier,
+) -> Result<i32> {
+    let host = &site.host;
+    match get_id(conn, &site.name, path, &settings.normalise) {
+        Ok(id) => Ok(id),
         Err(err) => {
             match err {
                 Error(ErrorKind::NoThread(_), _) => {
-                    verify_post(host, path)?;
+                    verifier.verify(host, path, &settings.normalise)?;
 
                     //We didn't find an id, but there was no error from the db.
                     //Create one.
-                    let opt_title = if title.is_empty() { None } else { Some(title) };
+                    let opt_title = current_title(host, path, title, settings.fetch_titles);
 
-                    let tid = create(conn, path, opt_title)?;
+                    let tid = create(
+                        conn,
+                        &site.name,
+                        &normalise(path, &settings.normalise),
+                        opt_title.as_ref().map(|t| t.as_str()),
+                    )?;
                     Ok(tid)
                 }
                 _ => Err(err),
@@ -52,28 +82,116 @@ pub fn gen_or_get_id(conn: &SqliteConnection, host: &str, title: &str, path: &st
     }
 }
 
-/// Checks that the path posted actually exists on the host.
-/// Should minimise the injection attack surface.
-fn verify_post(host: &str, path: &str) -> Result<()> {
-    // We use reqwest to handle the request for now, but may drop down to hyper later on.
-    let res = reqwest::get(&format!("{}{}", host, path)).chain_err(|| ErrorKind::Request)?;
+/// Updates the title of the thread `tid` once a live comment has been left on it, as the post
+/// may have been retitled since the thread was created. The `title` sent along with the comment
+/// is only trusted if titles aren't read from the post itself.
+pub fn update_title(
+    conn: &SqliteConnection,
+    site: &Site,
+    tid: i32,
+    title: &str,
+    path: &str,
+    settings: &Threads,
+) -> Result<()> {
+    let stored = threads::table
+        .select(threads::title)
+        .filter(threads::id.eq(tid))
+        .first::<Option<String>>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    if !title.is_empty() && stored.as_ref().map(|t| t.as_str()) != Some(title) {
+        let current = current_title(&site.host, path, title, settings.fetch_titles);
+        if current.is_some() && current != stored {
+            set_title(conn, tid, current.as_ref().map(|t| t.as_str()))?;
+        }
+    }
+    Ok(())
+}
 
-    if res.status() == reqwest::StatusCode::Ok {
-        Ok(())
+/// Works out the title a thread should have. If `fetch` is set, this is read from the post
+/// itself when possible, otherwise the `title` sent along with a comment is used.
+fn current_title(host: &str, path: &str, title: &str, fetch: bool) -> Option<String> {
+    let fetched = if fetch {
+        fetch_title(host, path).unwrap_or(None)
     } else {
-        Err(ErrorKind::PathCheckFailed.into())
+        None
+    };
+    fetched.or_else(|| {
+        if title.is_empty() {
+            None
+        } else {
+            Some(title.to_string())
+        }
+    })
+}
+
+/// Reads the contents of the `<title>` tag of a post on the host.
+fn fetch_title(host: &str, path: &str) -> Result<Option<String>> {
+    lazy_static! {
+        static ref TITLE: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
     }
+    let mut res = reqwest::get(&format!("{}{}", host.trim_right_matches('/'), path))
+        .chain_err(|| ErrorKind::Request)?;
+    if res.status() != reqwest::StatusCode::Ok {
+        return Err(ErrorKind::PathCheckFailed.into());
+    }
+    let page = res.text().chain_err(|| ErrorKind::Request)?;
+
+    let title = TITLE.captures(&page).and_then(|caps| caps.get(1)).map(|m| {
+        //Collapse whitespace and decode the most common entities
+        m.as_str()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&")
+    });
+    Ok(title.and_then(|t| if t.is_empty() { None } else { Some(t) }))
 }
 
-/// Saves a new thread for URI into the database. Returns the id of the new record.
+/// Updates the stored title of a thread.
+fn set_title(conn: &SqliteConnection, tid: i32, new_title: Option<&str>) -> Result<()> {
+    diesel::update(threads::table.filter(threads::id.eq(tid)))
+        .set(threads::title.eq(new_title))
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(())
+}
+
+/// Reads the title of every post on the site, updating any thread titles which have changed.
+/// Returns the number of threads which were updated.
+pub fn resync_titles(conn: &SqliteConnection, site: &Site) -> Result<usize> {
+    let all_threads = threads::table
+        .filter(threads::site.eq(&site.name))
+        .load::<Thread>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    let mut updated = 0;
+    for thread in all_threads {
+        //Posts which can't be read right now are skipped rather than cleared
+        if let Ok(Some(title)) = fetch_title(&site.host, &thread.uri) {
+            if thread.title.as_ref() != Some(&title) {
+                set_title(conn, thread.id, Some(&title))?;
+                updated += 1;
+            }
+        }
+    }
+    Ok(updated)
+}
+
+/// Saves a new thread for URI on a site into the database. Returns the id of the new record.
 fn create<'t>(
     conn: &SqliteConnection,
+    site_name: &'t str,
     new_url: &'t str,
     new_title: Option<&'t str>,
 ) -> Result<i32> {
     use schema::threads;
 
     let new_thread = NewThread {
+        site: site_name,
         uri: new_url,
         title: new_title,
     };
@@ -95,17 +213,412 @@ fn create<'t>(
     }
 }
 
-/// Returns the id of a thread from the database for a given URI.
-fn get_id(conn: &SqliteConnection, find_uri: &str) -> Result<i32> {
+/// Gets the id of the thread at exactly `find_uri` on the site `site_name`, creating it if
+/// it no longer exists. Used to put back comments whose thread was removed along with them,
+/// so it may be called from within a transaction. A recreated thread is open and has no
+/// aliases, as neither is kept once the original thread is removed.
+pub fn find_or_create(
+    conn: &SqliteConnection,
+    site_name: &str,
+    find_uri: &str,
+    title: Option<&str>,
+) -> QueryResult<i32> {
+    let found = threads::table
+        .select(threads::id)
+        .filter(threads::site.eq(site_name).and(threads::uri.eq(find_uri)))
+        .first::<i32>(conn)
+        .optional()?;
+    if let Some(tid) = found {
+        return Ok(tid);
+    }
+
+    let new_thread = NewThread {
+        site: site_name,
+        uri: find_uri,
+        title,
+    };
+    diesel::insert_into(threads::table)
+        .values(&new_thread)
+        .execute(conn)?;
+    threads::table
+        .select(threads::id)
+        .order(threads::id.desc())
+        .first::<i32>(conn)
+}
+
+/// Checks if a thread is closed to new comments, votes and edits. Threads are closed manually,
+/// or automatically once `close_after` days have passed since their first comment. Only manual
+/// closures are stored, so changing `close_after` will reopen automatically closed threads.
+/// Threads which have been reopened manually are never closed automatically.
+pub fn is_closed(conn: &SqliteConnection, tid: i32, close_after: u32) -> Result<bool> {
+    let (closed, reopened) = threads::table
+        .select((threads::closed, threads::reopened))
+        .filter(threads::id.eq(tid))
+        .first::<(bool, bool)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    if closed || reopened || close_after == 0 {
+        return Ok(closed);
+    }
+
+    let first_comment = comments::table
+        .select(min(comments::created))
+        .filter(comments::tid.eq(tid))
+        .first::<Option<NaiveDateTime>>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    let expired = first_comment.map_or(false, |created| {
+        Utc::now().naive_utc() - created > Duration::days(close_after.into())
+    });
+    Ok(expired)
+}
+
+/// Opens or closes a thread. A thread which is opened stays open, even once it would
+/// otherwise be closed automatically.
+pub fn set_closed(conn: &SqliteConnection, tid: i32, closed: bool) -> Result<()> {
+    diesel::update(threads::table.filter(threads::id.eq(tid)))
+        .set((threads::closed.eq(closed), threads::reopened.eq(!closed)))
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(())
+}
+
+/// Returns the id of a thread from the database for a given URI on the site `site_name`.
+/// The normalised form of the URI is preferred, although threads stored before
+/// the normalisation `rules` changed are still found via their original URI.
+/// If no thread has this URI, aliases of threads which have moved are checked.
+pub fn get_id(
+    conn: &SqliteConnection,
+    site_name: &str,
+    find_uri: &str,
+    rules: &Normalise,
+) -> Result<i32> {
     use schema::threads::dsl::*;
 
+    let normalised = normalise(find_uri, rules);
     let thread_info = threads
-        .filter(uri.eq(find_uri.to_string()))
+        .filter(site.eq(site_name))
+        .filter(uri.eq(&normalised).or(uri.eq(find_uri)))
         .load::<Thread>(conn)
         .chain_err(|| ErrorKind::DBRead)?;
-    if thread_info.len() == 1 {
-        Ok(thread_info[0].id)
+    match thread_info.iter().find(|t| t.uri == normalised) {
+        Some(thread) => Ok(thread.id),
+        None if thread_info.len() == 1 => Ok(thread_info[0].id),
+        None => get_alias(conn, site_name, find_uri, &normalised),
+    }
+}
+
+/// Returns the ids of threads on the site `site_name` for many URIs at once, following the
+/// same rules as `get_id`. URIs without a thread are left out.
+pub fn get_ids(
+    conn: &SqliteConnection,
+    site_name: &str,
+    find_uris: &[String],
+    rules: &Normalise,
+) -> Result<HashMap<String, i32>> {
+    let normalised: Vec<String> = find_uris.iter().map(|uri| normalise(uri, rules)).collect();
+    let candidates: Vec<String> = normalised.iter().chain(find_uris.iter()).cloned().collect();
+
+    let found: HashMap<String, i32> = threads::table
+        .select((threads::uri, threads::id))
+        .filter(threads::site.eq(site_name))
+        .filter(threads::uri.eq_any(&candidates))
+        .load::<(String, i32)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?
+        .into_iter()
+        .collect();
+    let aliased: HashMap<String, i32> = thread_aliases::table
+        .select((thread_aliases::uri, thread_aliases::tid))
+        .filter(thread_aliases::site.eq(site_name))
+        .filter(thread_aliases::uri.eq_any(&candidates))
+        .load::<(String, i32)>(conn)
+        .chain_err(|| ErrorKind::DBRead)?
+        .into_iter()
+        .collect();
+
+    let ids = find_uris
+        .iter()
+        .zip(normalised.iter())
+        .filter_map(|(uri, normal)| {
+            found
+                .get(normal)
+                .or_else(|| found.get(uri))
+                .or_else(|| aliased.get(normal))
+                .or_else(|| aliased.get(uri))
+                .map(|&tid| (uri.to_string(), tid))
+        }).collect();
+    Ok(ids)
+}
+
+/// Returns the id of the thread a URI on the site `site_name` is an alias of.
+fn get_alias(
+    conn: &SqliteConnection,
+    site_name: &str,
+    find_uri: &str,
+    normalised: &str,
+) -> Result<i32> {
+    let aliased = thread_aliases::table
+        .select(thread_aliases::tid)
+        .filter(thread_aliases::site.eq(site_name))
+        .filter(
+            thread_aliases::uri
+                .eq(normalised)
+                .or(thread_aliases::uri.eq(find_uri)),
+        )
+        .first::<i32>(conn)
+        .optional()
+        .chain_err(|| ErrorKind::DBRead)?;
+    aliased.ok_or_else(|| ErrorKind::NoThread(find_uri.to_string()).into())
+}
+
+/// Returns the site and URI of a thread.
+fn location(conn: &SqliteConnection, tid: i32) -> Result<(String, String)> {
+    threads::table
+        .select((threads::site, threads::uri))
+        .filter(threads::id.eq(tid))
+        .first::<(String, String)>(conn)
+        .chain_err(|| ErrorKind::DBRead)
+}
+
+/// Gets the title of the thread `tid`, if it has one.
+pub fn title(conn: &SqliteConnection, tid: i32) -> Result<Option<String>> {
+    threads::table
+        .select(threads::title)
+        .filter(threads::id.eq(tid))
+        .first::<Option<String>>(conn)
+        .chain_err(|| ErrorKind::DBRead)
+}
+
+/// Checks that a normalised URI on the site `site_name` does not already belong to a thread
+/// other than `tid`.
+fn check_available(
+    conn: &SqliteConnection,
+    tid: i32,
+    site_name: &str,
+    new_uri: &str,
+) -> Result<()> {
+    let thread_uses = threads::table
+        .filter(threads::site.eq(site_name))
+        .filter(threads::uri.eq(new_uri).and(threads::id.ne(tid)))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    let alias_uses = thread_aliases::table
+        .filter(thread_aliases::site.eq(site_name))
+        .filter(thread_aliases::uri.eq(new_uri).and(thread_aliases::tid.ne(tid)))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    if thread_uses + alias_uses > 0 {
+        Err(ErrorKind::UriTaken(new_uri.to_string()).into())
     } else {
-        Err(ErrorKind::NoThread(find_uri.to_string()).into())
+        Ok(())
+    }
+}
+
+/// Moves a thread to `new_uri`, for when a post has been renamed. The previous URI is kept as
+/// an alias, so links to the old location still find the thread.
+pub fn rename(conn: &SqliteConnection, tid: i32, new_uri: &str, rules: &Normalise) -> Result<()> {
+    let new_uri = normalise(new_uri, rules);
+    let (site_name, old_uri) = location(conn, tid)?;
+    check_available(conn, tid, &site_name, &new_uri)?;
+    if old_uri == new_uri {
+        return Ok(());
+    }
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(
+            thread_aliases::table
+                .filter(thread_aliases::site.eq(&site_name))
+                .filter(thread_aliases::uri.eq(&new_uri)),
+        ).execute(conn)?;
+        diesel::update(threads::table.filter(threads::id.eq(tid)))
+            .set(threads::uri.eq(&new_uri))
+            .execute(conn)?;
+        diesel::replace_into(thread_aliases::table)
+            .values((
+                thread_aliases::site.eq(&site_name),
+                thread_aliases::uri.eq(&old_uri),
+                thread_aliases::tid.eq(tid),
+            )).execute(conn)?;
+        Ok(())
+    }).chain_err(|| ErrorKind::DBRead)
+}
+
+/// Attaches an additional URI to a thread, for old locations of a post.
+pub fn add_alias(conn: &SqliteConnection, tid: i32, alias: &str, rules: &Normalise) -> Result<()> {
+    let alias = normalise(alias, rules);
+    let (site_name, uri) = location(conn, tid)?;
+    check_available(conn, tid, &site_name, &alias)?;
+    if uri == alias {
+        //This is already the thread's URI
+        return Ok(());
+    }
+
+    diesel::replace_into(thread_aliases::table)
+        .values((
+            thread_aliases::site.eq(&site_name),
+            thread_aliases::uri.eq(&alias),
+            thread_aliases::tid.eq(tid),
+        ))
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBInsert)?;
+    Ok(())
+}
+
+/// Merges threads of the same site whose URIs are identical once normalised, and stores the
+/// normalised URI of all remaining threads. This should be run whenever the normalisation `rules` change.
+/// Returns the number of threads which were merged into another.
+pub fn merge_duplicates(conn: &SqliteConnection, rules: &Normalise) -> Result<usize> {
+    let all_threads = threads::table
+        .order(threads::id.asc())
+        .load::<Thread>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    let mut groups: BTreeMap<(String, String), Vec<Thread>> = BTreeMap::new();
+    for thread in all_threads {
+        groups
+            .entry((thread.site.clone(), normalise(&thread.uri, rules)))
+            .or_insert_with(Vec::new)
+            .push(thread);
+    }
+
+    let mut merged = 0;
+    for ((site_name, normalised), group) in groups {
+        //Threads are ordered by id, so the oldest thread keeps its id
+        let mut group = group.into_iter();
+        let target = match group.next() {
+            Some(thread) => thread,
+            None => continue,
+        };
+        for duplicate in group {
+            merge(conn, duplicate.id, target.id)?;
+            merged += 1;
+        }
+        if target.uri != normalised {
+            diesel::update(threads::table.filter(threads::id.eq(target.id)))
+                .set(threads::uri.eq(&normalised))
+                .execute(conn)
+                .chain_err(|| ErrorKind::DBRead)?;
+        }
+        //Merged duplicates may have left an alias identical to the new URI
+        diesel::delete(
+            thread_aliases::table
+                .filter(thread_aliases::site.eq(&site_name))
+                .filter(thread_aliases::uri.eq(&normalised)),
+        ).execute(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    }
+    Ok(merged)
+}
+
+/// Moves all comments and reactions from thread `from` into thread `into`, then removes `from`.
+/// The URI and aliases of `from` become aliases of `into`.
+pub fn merge(conn: &SqliteConnection, from: i32, into: i32) -> Result<()> {
+    if from == into {
+        return Ok(());
+    }
+    let (site_name, from_uri) = location(conn, from)?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(comments::table.filter(comments::tid.eq(from)))
+            .set(comments::tid.eq(into))
+            .execute(conn)?;
+        diesel::update(thread_aliases::table.filter(thread_aliases::tid.eq(from)))
+            .set(thread_aliases::tid.eq(into))
+            .execute(conn)?;
+        //Readers who reacted to both threads keep a single reaction
+        sql_query("UPDATE OR IGNORE thread_reactions SET tid = ? WHERE tid = ?")
+            .bind::<Integer, _>(into)
+            .bind::<Integer, _>(from)
+            .execute(conn)?;
+        diesel::delete(thread_reactions::table.filter(thread_reactions::tid.eq(from)))
+            .execute(conn)?;
+        diesel::delete(threads::table.filter(threads::id.eq(from))).execute(conn)?;
+        diesel::replace_into(thread_aliases::table)
+            .values((
+                thread_aliases::site.eq(&site_name),
+                thread_aliases::uri.eq(&from_uri),
+                thread_aliases::tid.eq(into),
+            )).execute(conn)?;
+        Ok(())
+    }).chain_err(|| ErrorKind::DBRead)
+}
+
+/// Applies the normalisation `rules` to a post's `path`, so that different URIs
+/// pointing to the same post share a thread.
+pub fn normalise(path: &str, rules: &Normalise) -> String {
+    let mut uri = path.trim().to_string();
+
+    if rules.fragment {
+        if let Some(idx) = uri.find('#') {
+            uri.truncate(idx);
+        }
+    }
+    //Only the path is modified by the remaining rules
+    let query = match uri.find('?') {
+        Some(idx) => {
+            let query = uri.split_off(idx);
+            if rules.query {
+                String::new()
+            } else {
+                query
+            }
+        }
+        None => String::new(),
+    };
+
+    if rules.percent_encoding {
+        uri = decode_unreserved(&uri);
+    }
+    if rules.lowercase {
+        uri = uri.to_lowercase();
+    }
+    if !uri.starts_with('/') {
+        uri.insert(0, '/');
+    }
+
+    let file_idx = uri.rfind('/').map_or(0, |idx| idx + 1);
+    if rules
+        .index_files
+        .iter()
+        .any(|index| &uri[file_idx..] == index.as_str())
+    {
+        uri.truncate(file_idx);
+    }
+    if rules.trailing_slash {
+        while uri.len() > 1 && uri.ends_with('/') {
+            uri.pop();
+        }
+    }
+
+    uri.push_str(&query);
+    uri
+}
+
+/// Decodes percent encoded characters from the unreserved set (RFC 3986, section 2.3),
+/// and uppercases the hex digits of all others so that equivalent encodings match.
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' && idx + 2 < bytes.len() {
+            let high = (bytes[idx + 1] as char).to_digit(16);
+            let low = (bytes[idx + 2] as char).to_digit(16);
+            if let (Some(high), Some(low)) = (high, low) {
+                let byte = (high * 16 + low) as u8;
+                let unreserved = byte.is_ascii_alphanumeric() || b"-._~".contains(&byte);
+                if unreserved {
+                    decoded.push(byte);
+                } else {
+                    decoded.extend(format!("%{:02X}", byte).into_bytes());
+                }
+                idx += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[idx]);
+        idx += 1;
     }
+    //Only ASCII has been substituted, so the result is always valid UTF-8
+    String::from_utf8(decoded).unwrap_or_else(|_| path.to_string())
 }
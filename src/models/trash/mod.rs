@@ -0,0 +1,226 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use errors::*;
+use models::audit::{self, Action, Snapshot};
+use models::threads;
+use schema::{comment_trash, comments};
+
+#[derive(Insertable, Debug)]
+#[table_name = "comment_trash"]
+/// Insertable reference to the comment_trash table.
+struct NewTrash<'t> {
+    /// Reference to the deleted comment.
+    cid: i32,
+    /// Name of the site the comment belonged to.
+    site: &'t str,
+    /// The comment as it was before deletion.
+    snapshot: String,
+    /// Timestamp of deletion.
+    deleted: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug)]
+/// Queryable reference to the comment_trash table, for a single site.
+struct StoredTrash {
+    /// Primary key.
+    id: i32,
+    /// Reference to the deleted comment.
+    cid: i32,
+    /// The comment as it was before deletion.
+    snapshot: String,
+    /// Timestamp of deletion.
+    deleted: NaiveDateTime,
+}
+
+#[derive(Serialize, Debug)]
+/// A deleted comment which can still be restored, as shown to administrators.
+pub struct TrashedComment {
+    /// Primary key, used to restore the comment.
+    id: i32,
+    /// Timestamp of deletion.
+    deleted: DateTime<Utc>,
+    /// The comment as it was before deletion.
+    comment: Snapshot,
+}
+
+#[derive(Insertable, AsChangeset, Debug)]
+#[table_name = "comments"]
+#[changeset_options(treat_none_as_null = "true")]
+/// A comment being put back from the trash.
+struct RestoredComment<'r> {
+    /// Primary key. Left for the database to choose if the original has since been reused.
+    id: Option<i32>,
+    /// Reference to Thread.
+    tid: i32,
+    /// Parent comment.
+    parent: Option<i32>,
+    /// Timestamp of creation.
+    created: NaiveDateTime,
+    /// Timestamp of the last edit.
+    modified: Option<NaiveDateTime>,
+    /// Moderation mode of the comment.
+    mode: i32,
+    /// Remote IP.
+    remote_addr: Option<&'r str>,
+    /// Actual comment.
+    text: &'r str,
+    /// Commentors author if given.
+    author: Option<&'r str>,
+    /// Commentors email address if given, encrypted.
+    email: Option<&'r str>,
+    /// Commentors website if given.
+    website: Option<&'r str>,
+    /// Commentors indentifier.
+    hash: &'r str,
+    /// Number of likes a comment has recieved.
+    likes: Option<i32>,
+    /// Number of dislikes a comment has recieved.
+    dislikes: Option<i32>,
+    /// Blind index of the commentors email address.
+    email_index: Option<&'r str>,
+}
+
+/// Keeps a copy of a comment which is about to be deleted. Called from within the
+/// transaction which deletes it.
+pub fn put(conn: &SqliteConnection, snapshot: &Snapshot) -> QueryResult<()> {
+    let trash = NewTrash {
+        cid: snapshot.id,
+        site: &snapshot.site,
+        snapshot: snapshot.encode()?,
+        deleted: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(comment_trash::table)
+        .values(&trash)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Permanently removes comments which have been in the trash for longer than `keep_days`.
+/// Returns the number of comments removed.
+pub fn prune(conn: &SqliteConnection, keep_days: u32) -> Result<usize> {
+    let cutoff = Utc::now().naive_utc() - Duration::days(i64::from(keep_days));
+    diesel::delete(comment_trash::table.filter(comment_trash::deleted.le(cutoff)))
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBRead)
+}
+
+/// Returns the comments in the trash of the site `site_name`, most recently deleted first.
+pub fn list(conn: &SqliteConnection, site_name: &str) -> Result<Vec<TrashedComment>> {
+    let trashed = comment_trash::table
+        .select((
+            comment_trash::id,
+            comment_trash::cid,
+            comment_trash::snapshot,
+            comment_trash::deleted,
+        ))
+        .filter(comment_trash::site.eq(site_name))
+        .order(comment_trash::id.desc())
+        .load::<StoredTrash>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+
+    trashed
+        .into_iter()
+        .map(|trash| {
+            Ok(TrashedComment {
+                id: trash.id,
+                deleted: DateTime::from_utc(trash.deleted, Utc),
+                comment: Snapshot::decode(&trash.snapshot)?,
+            })
+        })
+        .collect()
+}
+
+/// Puts the comment held in the trash entry `id` of the site `site_name` back where it was,
+/// recreating its thread if needed, though without the closed state or aliases it had. The
+/// restore is recorded in the audit log as an action of the `actor`. Returns the id of the
+/// restored comment.
+pub fn restore(conn: &SqliteConnection, site_name: &str, id: i32, actor: &str) -> Result<i32> {
+    let trash = comment_trash::table
+        .select((
+            comment_trash::id,
+            comment_trash::cid,
+            comment_trash::snapshot,
+            comment_trash::deleted,
+        ))
+        .filter(comment_trash::id.eq(id).and(comment_trash::site.eq(site_name)))
+        .first::<StoredTrash>(conn)
+        .optional()
+        .chain_err(|| ErrorKind::DBRead)?
+        .ok_or_else(|| Error::from(ErrorKind::NotInTrash(id)))?;
+    let snapshot = Snapshot::decode(&trash.snapshot)?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let tid = threads::find_or_create(
+            conn,
+            &snapshot.site,
+            &snapshot.uri,
+            snapshot.title.as_ref().map(String::as_str),
+        )?;
+        //Replies to a comment which has since gone are shown at the top level instead
+        let parent = match snapshot.parent {
+            Some(pid) => comments::table
+                .select(comments::id)
+                .filter(comments::id.eq(pid))
+                .first::<i32>(conn)
+                .optional()?,
+            None => None,
+        };
+        //A comment with replies is blanked rather than removed, and must be filled in again
+        let existing = comments::table
+            .select(comments::mode)
+            .filter(comments::id.eq(trash.cid))
+            .first::<i32>(conn)
+            .optional()?;
+
+        let mut restored = RestoredComment {
+            id: Some(trash.cid),
+            tid,
+            parent,
+            created: snapshot.created,
+            modified: snapshot.modified,
+            mode: snapshot.mode,
+            remote_addr: snapshot.remote_addr.as_ref().map(String::as_str),
+            text: &snapshot.text,
+            author: snapshot.author.as_ref().map(String::as_str),
+            email: snapshot.email.as_ref().map(String::as_str),
+            website: snapshot.website.as_ref().map(String::as_str),
+            hash: &snapshot.hash,
+            likes: snapshot.likes,
+            dislikes: snapshot.dislikes,
+            email_index: snapshot.email_index.as_ref().map(String::as_str),
+        };
+        let cid = match existing {
+            Some(2) => {
+                diesel::update(comments::table.filter(comments::id.eq(trash.cid)))
+                    .set(&restored)
+                    .execute(conn)?;
+                trash.cid
+            }
+            Some(_) => {
+                //The id has since been given to another comment
+                restored.id = None;
+                diesel::insert_into(comments::table)
+                    .values(&restored)
+                    .execute(conn)?;
+                comments::table
+                    .select(comments::id)
+                    .order(comments::id.desc())
+                    .first::<i32>(conn)?
+            }
+            None => {
+                diesel::insert_into(comments::table)
+                    .values(&restored)
+                    .execute(conn)?;
+                trash.cid
+            }
+        };
+
+        diesel::delete(comment_trash::table.filter(comment_trash::id.eq(trash.id)))
+            .execute(conn)?;
+        audit::record(conn, &Snapshot::take(conn, cid)?, actor, Action::Restore)?;
+        Ok(cid)
+    }).chain_err(|| ErrorKind::DBInsert)
+}
@@ -0,0 +1,45 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use errors::*;
+use schema::trusted;
+
+#[derive(Insertable, Debug)]
+#[table_name = "trusted"]
+/// Insertable reference to the trusted table.
+struct NewTrusted<'t> {
+    /// Commentors identifier hash.
+    hash: &'t str,
+    /// Timestamp of when the commentor was first approved.
+    created: NaiveDateTime,
+}
+
+/// Checks if a commentor has had a comment approved previously.
+pub fn contains(conn: &SqliteConnection, hash: &str) -> Result<bool> {
+    let found = trusted::table
+        .filter(trusted::hash.eq(hash))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    Ok(found > 0)
+}
+
+/// Remembers a commentor as trusted, so their future comments are published immediately.
+pub fn insert(conn: &SqliteConnection, hash: &str) -> Result<()> {
+    //Anonymous commentors without an IP have no identity to trust
+    if hash.is_empty() || contains(conn, hash)? {
+        return Ok(());
+    }
+
+    let entry = NewTrusted {
+        hash,
+        created: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(trusted::table)
+        .values(&entry)
+        .execute(conn)
+        .chain_err(|| ErrorKind::DBInsert)?;
+    Ok(())
+}
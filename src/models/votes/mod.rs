@@ -0,0 +1,103 @@
+use chrono::{NaiveDateTime, Utc};
+use crypto::digest::Digest;
+use crypto::sha2::Sha224;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use errors::*;
+use schema::{comments, votes};
+
+#[derive(Insertable, Debug)]
+#[table_name = "votes"]
+/// Insertable reference to the votes table.
+struct NewVote<'v> {
+    /// Reference to the voted comment.
+    cid: i32,
+    /// Salted hash identifying the voter.
+    voter: &'v str,
+    /// True for a like, false for a dislike.
+    upvote: bool,
+    /// Timestamp of the vote.
+    created: NaiveDateTime,
+}
+
+/// Identifies a voter by their IP address, hashed with the `salt` from the configuration
+/// file so that addresses cannot be recovered from the votes table.
+pub fn voter_identity(ip_addr: &str, salt: &str) -> String {
+    let mut hasher = Sha224::new();
+    hasher.input_str(salt);
+    hasher.input_str(ip_addr);
+    hasher.result_str()
+}
+
+/// Returns the direction of a voter's current vote on a comment, if they have voted.
+fn current(conn: &SqliteConnection, cid: i32, voter: &str) -> QueryResult<Option<bool>> {
+    votes::table
+        .select(votes::upvote)
+        .filter(votes::cid.eq(cid).and(votes::voter.eq(voter)))
+        .first::<bool>(conn)
+        .optional()
+}
+
+/// Records a vote on a live comment and updates its tally. Voting the same way twice retracts
+/// the vote, and voting the other way changes it.
+/// Returns the direction of the voter's vote afterwards, or `None` if it was retracted.
+pub fn cast(conn: &SqliteConnection, cid: i32, voter: &str, upvote: bool) -> Result<Option<bool>> {
+    //Hidden comments are treated as missing, so voting doesn't reveal them
+    let live = comments::table
+        .filter(comments::id.eq(cid).and(comments::mode.eq(0)))
+        .count()
+        .first::<i64>(conn)
+        .chain_err(|| ErrorKind::DBRead)?;
+    if live == 0 {
+        return Err(ErrorKind::NoComment(cid).into());
+    }
+    let target = || comments::table.filter(comments::id.eq(cid));
+    let vote = || votes::table.filter(votes::cid.eq(cid).and(votes::voter.eq(voter)));
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        //Read within the transaction, so concurrent votes can't both apply the same change
+        let previous = current(conn, cid, voter)?;
+        //Remove the previous vote from the tally
+        match previous {
+            Some(true) => diesel::update(target())
+                .set(comments::likes.eq(comments::likes - 1))
+                .execute(conn)?,
+            Some(false) => diesel::update(target())
+                .set(comments::dislikes.eq(comments::dislikes - 1))
+                .execute(conn)?,
+            None => 0,
+        };
+        if previous == Some(upvote) {
+            diesel::delete(vote()).execute(conn)?;
+            return Ok(None);
+        }
+
+        if upvote {
+            diesel::update(target())
+                .set(comments::likes.eq(comments::likes + 1))
+                .execute(conn)?;
+        } else {
+            diesel::update(target())
+                .set(comments::dislikes.eq(comments::dislikes + 1))
+                .execute(conn)?;
+        }
+        if previous.is_some() {
+            diesel::update(vote())
+                .set(votes::upvote.eq(upvote))
+                .execute(conn)?;
+        } else {
+            let new_vote = NewVote {
+                cid,
+                voter,
+                upvote,
+                created: Utc::now().naive_utc(),
+            };
+            diesel::insert_into(votes::table)
+                .values(&new_vote)
+                .execute(conn)?;
+        }
+        Ok(Some(upvote))
+    }).chain_err(|| ErrorKind::DBInsert)
+}
@@ -1,11 +1,11 @@
 use lettre::smtp::authentication::{Credentials, Mechanism};
 use lettre::{EmailTransport, SmtpTransport};
-use lettre_email::EmailBuilder;
+use lettre_email::{Email, EmailBuilder};
 use reqwest;
 use std::collections::HashMap;
 
 use config::{Notifications, Telegram};
-use data::FormInput;
+use data::{FlagReport, FormInput};
 use errors::*;
 use regex::Regex;
 
@@ -21,6 +21,15 @@ fn get_domain(host: &str) -> &str {
     caps.get(2).map_or("noreply", |m| m.as_str())
 }
 
+/// Generates the name the notification recipient will be addressed by.
+fn recipient_name(notify: &Notifications) -> String {
+    if notify.recipient.name == "~" {
+        "Oration Admin".to_string()
+    } else {
+        notify.recipient.name.to_owned()
+    }
+}
+
 /// Sends an email to a recipient listed in the configuration file when a new comment is posted, so
 /// long as the notification system is enabled (this check is elsewhere).
 pub fn send_notification(
@@ -29,17 +38,19 @@ pub fn send_notification(
     host: &str,
     blog_name: &str,
     ip_addr: &str,
+    pending: bool,
+    password: &str,
 ) -> Result<()> {
     let post_url = format!("{}{}", host.trim_right_matches('/'), form.path);
     let oration_addr = format!("oration@{}", get_domain(host));
-    let recipient_name = if notify.recipient.name == "~" {
-        "Oration Admin".to_string()
+    let status = if pending {
+        "It is awaiting your approval."
     } else {
-        notify.recipient.name.to_owned()
+        "It is now live on your blog."
     };
 
     let email = EmailBuilder::new()
-        .to((notify.recipient.email.to_owned(), recipient_name))
+        .to((notify.recipient.email.to_owned(), recipient_name(notify)))
         .from((oration_addr, "Oration Watchdog"))
         .reply_to((form.sender_email(), form.sender_name()))
         .subject(format!("A new comment has been posted on {}", blog_name))
@@ -49,28 +60,104 @@ pub fn send_notification(
 The comment reads:
 {}
 
+{}
 You may reply on your blog post ({}), or if the user has left an email address, responding to this message will deliver them an email.
 
 Debug information:
 {:?}
 
 Commenter's IP: {}",
-                form.sender_name(), form.title, form.comment, post_url, form, ip_addr))
+                form.sender_name(), form.title, form.comment, status, post_url, form, ip_addr))
+        .build()
+        .chain_err(|| ErrorKind::BuildEmail)?;
+
+    deliver(&email, notify, password)
+}
+
+/// Sends an email to a recipient listed in the configuration file when a reader flags a comment, so
+/// long as the notification system is enabled (this check is elsewhere).
+pub fn send_flag_notification(
+    report: &FlagReport,
+    notify: &Notifications,
+    host: &str,
+    blog_name: &str,
+    password: &str,
+) -> Result<()> {
+    let post_url = format!("{}{}", host.trim_right_matches('/'), report.path);
+    let oration_addr = format!("oration@{}", get_domain(host));
+    let moderated = if report.moderated {
+        "It has been flagged too many times and is now hidden, awaiting moderation."
+    } else {
+        "It is still visible on your blog."
+    };
+
+    let email = EmailBuilder::new()
+        .to((notify.recipient.email.to_owned(), recipient_name(notify)))
+        .from((oration_addr, "Oration Watchdog"))
+        .subject(format!("A comment has been flagged on {}", blog_name))
+        .text(format!(
+"Comment {} on a post titled: {} has been flagged {} time(s).
+
+The comment reads:
+{}
+
+The latest report reads:
+{}
+
+{} You can find the post here: {}",
+                report.id, report.title, report.count, report.text, report.reason, moderated, post_url))
+        .build()
+        .chain_err(|| ErrorKind::BuildEmail)?;
+
+    deliver(&email, notify, password)
+}
+
+/// Sends a commentor a link which verifies the email address they supplied with their comment.
+pub fn send_verification(
+    form: &FormInput,
+    link: &str,
+    notify: &Notifications,
+    host: &str,
+    blog_name: &str,
+    password: &str,
+) -> Result<()> {
+    let oration_addr = format!("oration@{}", get_domain(host));
+
+    let email = EmailBuilder::new()
+        .to((form.sender_email(), form.sender_name()))
+        .from((oration_addr, blog_name))
+        .subject(format!("Please verify your email address for {}", blog_name))
+        .text(format!(
+"Hi {},
+
+Thank you for commenting on the post titled: {}.
+
+Before your comment is published, we need to confirm this email address belongs to you. Please follow this link to do so:
+{}
+
+Once verified, your future comments will be published straight away. If you did not leave this comment, you can safely ignore this message.",
+                form.sender_name(), form.title, link))
         .build()
         .chain_err(|| ErrorKind::BuildEmail)?;
 
+    deliver(&email, notify, password)
+}
+
+/// Connects to the SMTP server listed in the configuration file and sends `email`,
+/// authenticating with `password`.
+fn deliver(email: &Email, notify: &Notifications, password: &str) -> Result<()> {
     // Connect to a remote server on a custom port
     let mut mailer = SmtpTransport::simple_builder(&notify.smtp_server.host)
         .chain_err(|| ErrorKind::BuildSmtpTransport)?
         // Add credentials for authentication
-        .credentials(Credentials::new(notify.smtp_server.user_name.to_owned(), notify.smtp_server.password.to_owned()))
+        .credentials(Credentials::new(notify.smtp_server.user_name.to_owned(), password.to_owned()))
         // Enable SMTPUTF8 if the server supports it
         .smtp_utf8(true)
         // Configure expected authentication mechanism
         .authentication_mechanism(Mechanism::Plain)
         .build();
 
-    mailer.send(&email).chain_err(|| ErrorKind::SendEmail)?;
+    mailer.send(email).chain_err(|| ErrorKind::SendEmail)?;
 
     Ok(())
 }
@@ -81,8 +168,14 @@ pub fn push_telegram(
     telegram: &Telegram,
     host: &str,
     ip_addr: &str,
+    pending: bool,
 ) -> Result<()> {
     let post_url = format!("{}{}", host.trim_right_matches('/'), form.path);
+    let status = if pending {
+        "It is awaiting your approval."
+    } else {
+        "It is now live on your blog."
+    };
     let message = format!(
 "A comment has been posted by *{}* on a post titled:
 _{}_.
@@ -90,21 +183,52 @@ _{}_.
 The comment reads:
 {}
 
+{}
 You may reply on your blog post [here]({}). In the future, this bot may also provide a means of responding.
 
 Debug information:
 `{:?}`
 
 Commenter's IP: {}",
-        form.sender_name(), form.title, form.comment, post_url, form, ip_addr);
+        form.sender_name(), form.title, form.comment, status, post_url, form, ip_addr);
     println!("{}", message);
+    push_message(&message, telegram)
+}
+
+/// Sends a push notification to a bot which will forward you a message about a flagged comment.
+pub fn push_telegram_flag(report: &FlagReport, telegram: &Telegram, host: &str) -> Result<()> {
+    let post_url = format!("{}{}", host.trim_right_matches('/'), report.path);
+    let moderated = if report.moderated {
+        "It is now hidden, awaiting moderation."
+    } else {
+        "It is still visible."
+    };
+    let message = format!(
+"Comment {} on a post titled:
+_{}_
+has been flagged {} time(s).
+
+The comment reads:
+{}
+
+The latest report reads:
+{}
+
+{} View the post [here]({}).",
+        report.id, report.title, report.count, report.text, report.reason, moderated, post_url);
+    push_message(&message, telegram)
+}
+
+/// Forwards `message` to the telegram bot listed in the configuration file.
+fn push_message(message: &str, telegram: &Telegram) -> Result<()> {
     let preview = String::from("1");
     let md = String::from("Markdown");
+    let text = message.to_string();
     let mut params = HashMap::new();
     params.insert("chat_id", &telegram.chat_id);
     params.insert("parse_mode", &md);
     params.insert("disable_web_page_preview", &preview);
-    params.insert("text", &message);
+    params.insert("text", &text);
 
     let res = reqwest::Client::new()
         .post(&format!(
@@ -0,0 +1,55 @@
+use regex::Regex;
+
+/// Turns the markdown of a comment into a plain text excerpt of at most `length` characters,
+/// for places where the comment can't be rendered in full. An ellipsis is added after those
+/// characters if the comment had to be cut.
+pub fn excerpt(text: &str, length: usize) -> String {
+    lazy_static! {
+        static ref IMAGE: Regex = Regex::new(r"!\[([^\]]*)\]\([^)]*\)").unwrap();
+        static ref LINK: Regex = Regex::new(r"\[([^\]]*)\]\([^)]*\)").unwrap();
+        static ref LINE_MARKS: Regex = Regex::new(r"(?m)^\s*(#{1,6}\s+|>\s?|[-*+]\s+|\d+\.\s+)").unwrap();
+        //Underscores only mark emphasis at the edges of words, not within snake_case
+        static ref INLINE_MARKS: Regex = Regex::new(r"[*~`]+|\b_+|_+\b").unwrap();
+        static ref TAGS: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+    let plain = IMAGE.replace_all(text, "$1");
+    let plain = LINK.replace_all(&plain, "$1");
+    let plain = LINE_MARKS.replace_all(&plain, "");
+    let plain = INLINE_MARKS.replace_all(&plain, "");
+    let plain = TAGS.replace_all(&plain, "");
+    let words: Vec<&str> = plain.split_whitespace().collect();
+
+    let mut excerpt = String::new();
+    for word in words {
+        let space = if excerpt.is_empty() { 0 } else { 1 };
+        if excerpt.chars().count() + space + word.chars().count() > length {
+            if excerpt.is_empty() {
+                //A single long word is cut rather than dropped
+                excerpt = word.chars().take(length).collect();
+            }
+            excerpt.push('…');
+            break;
+        }
+        if !excerpt.is_empty() {
+            excerpt.push(' ');
+        }
+        excerpt.push_str(word);
+    }
+    excerpt
+}
+
+/// Escapes text so it can be placed into HTML or XML.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,32 @@
+use db::Pool;
+use models::privacy;
+use std::thread;
+use std::time::Duration;
+use yansi::Paint;
+
+/// How often stored IP addresses are checked for expiry.
+const PURGE_INTERVAL: u64 = 60 * 60;
+
+/// Starts a background job which removes IP addresses stored alongside comments once they
+/// are older than `days`. Nothing is started if `days` is 0, as addresses are then kept forever.
+/// The `salt` is used to hash the addresses that flags still need.
+pub fn spawn(pool: Pool, days: u32, salt: String) {
+    if days == 0 {
+        return;
+    }
+    thread::spawn(move || loop {
+        match pool.get() {
+            Ok(conn) => match privacy::forget_addresses(&conn, days, &salt) {
+                Ok(0) => {}
+                Ok(count) => log::info!(
+                    "🧹  {} {}",
+                    Paint::blue("Removed expired IP addresses from comments:"),
+                    count
+                ),
+                Err(ref err) => ::print_errors(err),
+            },
+            Err(err) => log::warn!("{}", err),
+        }
+        thread::sleep(Duration::from_secs(PURGE_INTERVAL));
+    });
+}
@@ -1,3 +1,49 @@
+table! {
+    audit_log (id) {
+        id -> Integer,
+        cid -> Integer,
+        site -> Text,
+        actor -> Text,
+        action -> Text,
+        snapshot -> Text,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    comment_reactions (cid, voter, reaction) {
+        cid -> Integer,
+        voter -> Text,
+        reaction -> Text,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    comment_revisions (id) {
+        id -> Integer,
+        cid -> Integer,
+        text -> Text,
+        author -> Nullable<Text>,
+        email -> Nullable<Text>,
+        website -> Nullable<Text>,
+        hash -> Text,
+        created -> Timestamp,
+        replaced -> Timestamp,
+        email_index -> Nullable<Text>,
+    }
+}
+
+table! {
+    comment_trash (id) {
+        id -> Integer,
+        cid -> Integer,
+        site -> Text,
+        snapshot -> Text,
+        deleted -> Timestamp,
+    }
+}
+
 table! {
     comments (id) {
         id -> Integer,
@@ -15,6 +61,28 @@ table! {
         likes -> Nullable<Integer>,
         dislikes -> Nullable<Integer>,
         voters -> Nullable<Binary>,
+        email_index -> Nullable<Text>,
+        browser -> Nullable<Text>,
+    }
+}
+
+table! {
+    emails (email, browser) {
+        email -> Text,
+        browser -> Text,
+        token -> Nullable<Text>,
+        verified -> Bool,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    flags (id) {
+        id -> Integer,
+        cid -> Integer,
+        remote_addr -> Text,
+        reason -> Text,
+        created -> Timestamp,
     }
 }
 
@@ -25,13 +93,66 @@ table! {
     }
 }
 
+table! {
+    trusted (hash) {
+        hash -> Text,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    thread_aliases (site, uri) {
+        site -> Text,
+        uri -> Text,
+        tid -> Integer,
+    }
+}
+
+table! {
+    thread_reactions (tid, voter, reaction) {
+        tid -> Integer,
+        voter -> Text,
+        reaction -> Text,
+        created -> Timestamp,
+    }
+}
+
 table! {
     threads (id) {
         id -> Integer,
+        site -> Text,
         uri -> Text,
         title -> Nullable<Text>,
+        closed -> Bool,
+        reopened -> Bool,
+    }
+}
+
+table! {
+    votes (cid, voter) {
+        cid -> Integer,
+        voter -> Text,
+        upvote -> Bool,
+        created -> Timestamp,
     }
 }
 
+joinable!(comment_reactions -> comments (cid));
+joinable!(comment_revisions -> comments (cid));
 joinable!(comments -> threads (tid));
-allow_tables_to_appear_in_same_query!(comments, threads);
+joinable!(flags -> comments (cid));
+joinable!(thread_aliases -> threads (tid));
+joinable!(thread_reactions -> threads (tid));
+joinable!(votes -> comments (cid));
+allow_tables_to_appear_in_same_query!(
+    audit_log,
+    comment_reactions,
+    comment_revisions,
+    comment_trash,
+    comments,
+    flags,
+    thread_aliases,
+    thread_reactions,
+    threads,
+    votes,
+);
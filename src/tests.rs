@@ -1,8 +1,92 @@
 use super::rocket;
+use chrono::{Duration, Utc};
+use diesel;
 use diesel::prelude::*;
+use errors;
 use rocket::http::Status;
 use rocket::local::Client;
 use schema::preferences::dsl::*;
+use serde_json;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// SQLite allows a single writer, so tests which write to the database take turns.
+    static ref WRITER: Mutex<()> = Mutex::new(());
+}
+
+/// Runs `test` in a transaction which is rolled back afterwards, leaving the database as it was.
+fn rolled_back<F>(test: F)
+where
+    F: FnOnce(&SqliteConnection) -> errors::Result<()>,
+{
+    let _writer = WRITER.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let conn = rocket().1;
+    conn.test_transaction::<_, errors::Error, _>(|| test(&conn));
+}
+
+/// Adds a thread for the post `uri` of the site `site`, returning its id.
+fn add_thread(conn: &SqliteConnection, site: &str, uri: &str) -> i32 {
+    use schema::threads;
+
+    diesel::insert_into(threads::table)
+        .values((threads::site.eq(site), threads::uri.eq(uri)))
+        .execute(conn)
+        .unwrap();
+    threads::table
+        .select(threads::id)
+        .filter(threads::site.eq(site).and(threads::uri.eq(uri)))
+        .first(conn)
+        .unwrap()
+}
+
+/// Adds a comment to the thread `tid`, left `age` minutes ago by the commentor `hash`
+/// from the address `addr`, returning its id.
+fn add_comment(
+    conn: &SqliteConnection,
+    tid: i32,
+    parent: Option<i32>,
+    mode: i32,
+    hash: &str,
+    addr: &str,
+    age: i64,
+) -> i32 {
+    use schema::comments;
+
+    let created = Utc::now().naive_utc() - Duration::minutes(age);
+    diesel::insert_into(comments::table)
+        .values((
+            comments::tid.eq(tid),
+            comments::parent.eq(parent),
+            comments::created.eq(created),
+            comments::mode.eq(mode),
+            comments::remote_addr.eq(addr),
+            comments::text.eq("A comment."),
+            comments::hash.eq(hash),
+        ))
+        .execute(conn)
+        .unwrap();
+    comments::table
+        .select(diesel::dsl::max(comments::id))
+        .first::<Option<i32>>(conn)
+        .unwrap()
+        .unwrap()
+}
+
+/// Ids of the listed `comments` and their replies, in the order they are listed.
+fn listed_ids(comments: &[::models::comments::NestedComment]) -> Vec<i64> {
+    fn collect(comment: &serde_json::Value, ids: &mut Vec<i64>) {
+        ids.push(comment["id"].as_i64().unwrap());
+        for child in comment["children"].as_array().unwrap() {
+            collect(child, ids);
+        }
+    }
+
+    let mut ids = Vec::new();
+    for comment in serde_json::to_value(comments).unwrap().as_array().unwrap() {
+        collect(comment, &mut ids);
+    }
+    ids
+}
 
 #[test]
 /// Tests connection to the database through the pool managed by rocket.
@@ -31,3 +115,969 @@ fn session_hash() {
     assert_eq!(response.status(), Status::Ok);
     assert_eq!(response.body_string().unwrap(), session_key[0]);
 }
+
+#[test]
+/// Checks that common variations of a post's URI normalise to the same thread.
+fn normalise_uris() {
+    use config::Normalise;
+    use models::threads::normalise;
+
+    let rules = Normalise {
+        trailing_slash: true,
+        index_files: vec!["index.html".to_string()],
+        query: true,
+        fragment: true,
+        percent_encoding: true,
+        lowercase: false,
+    };
+
+    for uri in &[
+        "/post",
+        "/post/",
+        "/post/index.html",
+        "/post/?page=2",
+        "/post#comment-4",
+        "/p%6Fst/",
+    ] {
+        assert_eq!(normalise(uri, &rules), "/post");
+    }
+    assert_eq!(normalise("/", &rules), "/");
+    assert_eq!(normalise("/index.html", &rules), "/");
+    assert_eq!(normalise("/a%2fb", &rules), "/a%2Fb");
+}
+
+#[test]
+/// Checks that preflight requests from the blog are answered, and those from elsewhere are not.
+fn cors_preflight() {
+    use rocket::http::Header;
+
+    let client = Client::new(rocket().0).expect("valid rocket instance");
+    let response = client
+        .options("/oration")
+        .header(Header::new("Origin", "http://localhost:8000"))
+        .header(Header::new("Access-Control-Request-Method", "POST"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(
+        response.headers().get_one("Access-Control-Allow-Origin"),
+        Some("http://localhost:8000")
+    );
+
+    let response = client
+        .options("/oration")
+        .header(Header::new("Origin", "http://elsewhere.example.com"))
+        .header(Header::new("Access-Control-Request-Method", "POST"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+}
+
+#[test]
+/// Checks that comment rankings account for the number of votes, not just their difference.
+fn comment_rankings() {
+    use models::comments::ranking::{best, controversial};
+
+    assert!(best(10, 0) > best(101, 100));
+    assert!(best(101, 100) > best(1, 0));
+    assert!(best(1, 0) > best(0, 0));
+    assert!(best(0, 0) == 0.0);
+
+    assert!(controversial(101, 100) > controversial(10, 5));
+    assert!(controversial(10, 5) > controversial(1, 0));
+    assert!(controversial(1, 0) == 0.0);
+}
+
+#[test]
+/// Checks that excerpts drop markdown formatting and are cut at a word boundary.
+fn comment_excerpts() {
+    use render::excerpt;
+
+    assert_eq!(
+        excerpt("# Hello\n\n**Bold** and [a link](http://example.com).", 100),
+        "Hello Bold and a link."
+    );
+    assert_eq!(
+        excerpt("Call _snake_case_ or __init__, *not* `code`", 100),
+        "Call snake_case or init, not code"
+    );
+    assert_eq!(excerpt("one two three", 9), "one two…");
+    assert_eq!(excerpt("abcdefgh", 4), "abcd…");
+}
+
+#[test]
+/// Checks that comment text is escaped before being placed into a feed.
+fn feed_escaping() {
+    use render::escape;
+
+    assert_eq!(
+        escape("<b>Tom & \"Jerry's\"</b>"),
+        "&lt;b&gt;Tom &amp; &quot;Jerry&#39;s&quot;&lt;/b&gt;"
+    );
+    assert_eq!(escape("plain text"), "plain text");
+}
+
+#[test]
+/// Checks that links to comments which do not exist are not found.
+fn missing_comment_permalink() {
+    let client = Client::new(rocket().0).expect("valid rocket instance");
+
+    let response = client.get("/oration/c/-1").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client.get("/oration/c/-1/context").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+/// Checks that feeds of blogs which are not configured are not found.
+fn unknown_feed_sites() {
+    let client = Client::new(rocket().0).expect("valid rocket instance");
+
+    let response = client.get("/oration/feed/recent?site=missing").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client.get("/oration/feed?url=/&site=missing").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+/// Checks that search terms are quoted so they can't be read as full text query syntax.
+fn search_expressions() {
+    use models::search::match_expression;
+
+    assert_eq!(
+        match_expression(Some("rust  OR \"sqlite"), None),
+        Some("\"rust\" \"OR\" \"\"\"sqlite\"".to_string())
+    );
+    assert_eq!(
+        match_expression(None, Some(" Jane Doe ")),
+        Some("author : \"Jane Doe\"".to_string())
+    );
+    assert_eq!(
+        match_expression(Some("moderation"), Some("jane")),
+        Some("\"moderation\" author : \"jane\"".to_string())
+    );
+    assert_eq!(match_expression(Some("   "), Some("")), None);
+}
+
+#[test]
+/// Checks that readers can't list revisions unless `public_revisions` is enabled, and that
+/// administrative revision requests need the admin key.
+fn revisions_access() {
+    let client = Client::new(rocket().0).expect("valid rocket instance");
+
+    let response = client.get("/oration/revisions?id=1").dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = client.get("/oration/admin/revisions?id=1").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+/// Checks that the trash and audit log can't be read without the admin key.
+fn trash_needs_admin() {
+    let client = Client::new(rocket().0).expect("valid rocket instance");
+
+    let response = client.get("/oration/admin/trash").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = client.get("/oration/admin/audit?limit=10").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+/// Checks that commentors are identified by email address or identifier hash, and that a
+/// blank subject is refused since erased comments have a blank identifier.
+fn privacy_subjects() {
+    use models::privacy::Subject;
+
+    match Subject::parse(" Jane@Example.com ") {
+        Some(Subject::Email(address)) => assert_eq!(address, "jane@example.com"),
+        other => panic!("expected an email address, got {:?}", other),
+    }
+    match Subject::parse("a1b2c3") {
+        Some(Subject::Hash(hash)) => assert_eq!(hash, "a1b2c3"),
+        other => panic!("expected a hash, got {:?}", other),
+    }
+    assert!(Subject::parse("  ").is_none());
+}
+
+#[test]
+/// Checks that hashed IP addresses stay comparable, without revealing the address.
+fn hashed_addresses() {
+    use models::privacy::hmac_address;
+
+    let hashed = hmac_address("salt", "192.0.2.1");
+    assert_eq!(hashed, hmac_address("salt", "192.0.2.1"));
+    assert_ne!(hashed, hmac_address("salt", "192.0.2.2"));
+    assert_ne!(hashed, hmac_address("pepper", "192.0.2.1"));
+    assert_eq!(hashed.len(), 64);
+    assert!(!hashed.contains('.') && !hashed.contains(':'));
+}
+
+#[test]
+/// Checks that email addresses are encrypted differently each time, yet still decrypt and
+/// can be looked up by their blind index.
+fn email_encryption() {
+    use cipher::{is_sealed, Cipher};
+
+    let cipher = Cipher::new("secret");
+    let sealed = cipher.encrypt("jane@example.com").unwrap();
+    assert!(is_sealed(&sealed));
+    assert!(!sealed.contains("jane"));
+    assert_ne!(sealed, cipher.encrypt("jane@example.com").unwrap());
+    assert_eq!(cipher.decrypt(&sealed).unwrap(), "jane@example.com");
+    assert!(Cipher::new("another secret").decrypt(&sealed).is_err());
+    //Addresses stored before encryption was introduced are read as they are
+    assert_eq!(cipher.decrypt("jane@example.com").unwrap(), "jane@example.com");
+
+    assert_eq!(cipher.index(" Jane@Example.com "), cipher.index("jane@example.com"));
+    assert_ne!(
+        cipher.index("jane@example.com"),
+        Cipher::new("another secret").index("jane@example.com")
+    );
+}
+
+#[test]
+/// Checks that comments of shadow banned commentors are only listed for the commentors
+/// themselves, whether they are recognised by their address or their identifier hash, even
+/// once their address is no longer stored.
+fn shadow_banned_comments() {
+    use config::ShadowBan;
+    use data::SortOrder;
+    use models::comments::{gen_hash, Listing, NestedComment};
+    use models::privacy::hmac_address;
+    use schema::comments;
+
+    let ban = ShadowBan {
+        hashes: vec!["banned".to_string()],
+        ips: vec!["192.0.2.9".to_string()],
+        emails: vec!["Banned@Example.com".to_string()],
+    };
+    assert!(ban.matches("banned", "192.0.2.1", None));
+    assert!(ban.matches("reader", "192.0.2.9", None));
+    assert!(ban.matches("reader", "192.0.2.1", Some("banned@example.com")));
+    assert!(!ban.matches("reader", "192.0.2.1", Some("reader@example.com")));
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/shadow-ban");
+        let live = add_comment(conn, tid, None, 0, "reader", "192.0.2.1", 2);
+        let hidden = add_comment(conn, tid, None, 3, "banned", "192.0.2.9", 1);
+        let listing = Listing {
+            sort: SortOrder::Oldest,
+            parent: None,
+            page: 0,
+            page_size: 0,
+            depth: None,
+        };
+
+        let list = |ip_addr: &str, stored_addr: &str, hash: Option<&str>| {
+            NestedComment::list(conn, tid, ip_addr, stored_addr, hash, &listing)
+                .map(|page| listed_ids(&page.comments))
+        };
+        let both = vec![live as i64, hidden as i64];
+        assert_eq!(list("192.0.2.1", "192.0.2.1", Some("reader"))?, vec![live as i64]);
+        assert_eq!(list("192.0.2.9", "192.0.2.9", None)?, both);
+        assert_eq!(list("192.0.2.1", "192.0.2.1", Some("banned"))?, both);
+
+        //Anonymous commentors are still recognised once their address is hashed or removed
+        let anonymous_hash = gen_hash(&None, &None, &None, Some("192.0.2.8"));
+        let anonymous = add_comment(conn, tid, None, 3, &anonymous_hash, "192.0.2.8", 1);
+        diesel::update(comments::table.filter(comments::id.eq(anonymous)))
+            .set(comments::remote_addr.eq(None::<String>))
+            .execute(conn)
+            .unwrap();
+        assert_eq!(
+            list("192.0.2.8", &hmac_address("salt", "192.0.2.8"), None)?,
+            vec![live as i64, anonymous as i64]
+        );
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that each address may only flag a comment once, and that a comment is moved into
+/// moderation once it has been flagged `flag_limit` times.
+fn flag_limits() {
+    use models::flags;
+    use schema::flags as flag_rows;
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/flags");
+        let cid = add_comment(conn, tid, None, 0, "author", "192.0.2.1", 1);
+
+        assert_eq!(flags::insert(conn, cid, "192.0.2.2", "salt", "Spam")?, 1);
+        match flags::insert(conn, cid, "192.0.2.2", "salt", "Still spam") {
+            Err(errors::Error(errors::ErrorKind::AlreadyFlagged, _)) => (),
+            other => panic!("expected a repeated flag to be refused, got {:?}", other),
+        }
+        assert_eq!(flags::insert(conn, cid, "192.0.2.3", "salt", "Rude")?, 2);
+        assert_eq!(flags::count(conn, cid)?, 2);
+        //Flags are stored hashed, but older ones may still hold the address itself
+        let stored: Vec<String> = flag_rows::table
+            .select(flag_rows::remote_addr)
+            .filter(flag_rows::cid.eq(cid))
+            .load(conn)
+            .unwrap();
+        assert!(stored.iter().all(|addr| addr != "192.0.2.2" && addr != "192.0.2.3"));
+        diesel::insert_into(flag_rows::table)
+            .values((
+                flag_rows::cid.eq(cid),
+                flag_rows::remote_addr.eq("192.0.2.5"),
+                flag_rows::reason.eq("Spam"),
+                flag_rows::created.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .unwrap();
+        match flags::insert(conn, cid, "192.0.2.5", "salt", "Spam") {
+            Err(errors::Error(errors::ErrorKind::AlreadyFlagged, _)) => (),
+            other => panic!("expected an unhashed flag to be matched, got {:?}", other),
+        }
+
+        assert!(!flags::enforce_limit(conn, cid, 2, 0)?);
+        assert!(!flags::enforce_limit(conn, cid, 2, 3)?);
+        assert!(flags::enforce_limit(conn, cid, 2, 2)?);
+        //The comment is no longer live, so can't be moved or flagged again
+        assert!(!flags::enforce_limit(conn, cid, 2, 2)?);
+        match flags::insert(conn, cid, "192.0.2.4", "salt", "Spam") {
+            Err(errors::Error(errors::ErrorKind::NoComment(_), _)) => (),
+            other => panic!("expected a held comment to be unflaggable, got {:?}", other),
+        }
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that the flags, votes and reactions of a deleted comment are removed along with it,
+/// as are the aliases of a thread which is removed once its last comment is.
+fn deleted_comment_dependents() {
+    use models::{flags, reactions, votes};
+    use schema::flags as flag_rows;
+    use schema::votes as vote_rows;
+    use schema::{comment_reactions, comments, thread_aliases};
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/dependents");
+        let cid = add_comment(conn, tid, None, 0, "author", "192.0.2.1", 1);
+        flags::insert(conn, cid, "192.0.2.2", "salt", "Spam")?;
+        votes::cast(conn, cid, "voter", true)?;
+        reactions::add_to_comment(conn, cid, "voter", "heart")?;
+        diesel::insert_into(thread_aliases::table)
+            .values((
+                thread_aliases::site.eq("test"),
+                thread_aliases::uri.eq("/old-dependents"),
+                thread_aliases::tid.eq(tid),
+            ))
+            .execute(conn)
+            .unwrap();
+
+        diesel::delete(comments::table.filter(comments::id.eq(cid)))
+            .execute(conn)
+            .unwrap();
+
+        let count_flags: i64 = flag_rows::table
+            .filter(flag_rows::cid.eq(cid))
+            .count()
+            .first(conn)
+            .unwrap();
+        let count_votes: i64 = vote_rows::table
+            .filter(vote_rows::cid.eq(cid))
+            .count()
+            .first(conn)
+            .unwrap();
+        let count_reactions: i64 = comment_reactions::table
+            .filter(comment_reactions::cid.eq(cid))
+            .count()
+            .first(conn)
+            .unwrap();
+        let count_aliases: i64 = thread_aliases::table
+            .filter(thread_aliases::tid.eq(tid))
+            .count()
+            .first(conn)
+            .unwrap();
+        assert_eq!((count_flags, count_votes, count_reactions), (0, 0, 0));
+        assert_eq!(count_aliases, 0);
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that the previous versions of a comment are removed when it is deleted, whether it is
+/// removed entirely or blanked because it has replies.
+fn deleted_comment_revisions() {
+    use models::comments::Comment;
+    use models::revisions;
+    use schema::comment_revisions;
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/revisions");
+        let parent = add_comment(conn, tid, None, 0, "author", "192.0.2.1", 3);
+        let reply = add_comment(conn, tid, Some(parent), 0, "author", "192.0.2.1", 2);
+        revisions::record(conn, parent).unwrap();
+        revisions::record(conn, reply).unwrap();
+
+        //The parent still has a reply so is blanked, then the reply is removed entirely
+        Comment::delete(conn, parent, "author", 0)?;
+        Comment::delete(conn, reply, "author", 0)?;
+
+        let count: i64 = comment_revisions::table
+            .filter(comment_revisions::cid.eq_any(vec![parent, reply]))
+            .count()
+            .first(conn)
+            .unwrap();
+        assert_eq!(count, 0);
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that deleted comments are put back from the trash, both a reply which was removed
+/// entirely along with its thread and a comment with replies which was blanked.
+fn restored_comments() {
+    use models::comments::Comment;
+    use models::trash;
+    use schema::{comment_trash, comments, threads};
+
+    /// Restores the trashed copy of the comment `cid`, returning the id it is restored as.
+    fn restore(conn: &SqliteConnection, cid: i32) -> errors::Result<i32> {
+        let id = comment_trash::table
+            .select(comment_trash::id)
+            .filter(comment_trash::cid.eq(cid))
+            .first::<i32>(conn)
+            .unwrap();
+        trash::restore(conn, "test", id, "admin")
+    }
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/restored");
+        let parent = add_comment(conn, tid, None, 0, "author", "192.0.2.1", 3);
+        let reply = add_comment(conn, tid, Some(parent), 0, "author", "192.0.2.1", 2);
+
+        //The parent has a reply so is blanked
+        Comment::delete(conn, parent, "admin", 30)?;
+        let (mode, text): (i32, String) = comments::table
+            .select((comments::mode, comments::text))
+            .filter(comments::id.eq(parent))
+            .first(conn)
+            .unwrap();
+        assert_eq!((mode, text.as_str()), (2, ""));
+        assert_eq!(restore(conn, parent)?, parent);
+        let (mode, text): (i32, String) = comments::table
+            .select((comments::mode, comments::text))
+            .filter(comments::id.eq(parent))
+            .first(conn)
+            .unwrap();
+        assert_eq!((mode, text.as_str()), (0, "A comment."));
+
+        //Removing the reply and then the parent leaves no comments, so the thread goes too
+        Comment::delete(conn, reply, "admin", 30)?;
+        Comment::delete(conn, parent, "admin", 30)?;
+        let count: i64 = comments::table
+            .filter(comments::id.eq_any(vec![parent, reply]))
+            .count()
+            .first(conn)
+            .unwrap();
+        assert_eq!(count, 0);
+
+        //Put back on its own, the reply is shown at the top level of a recreated thread
+        let restored = restore(conn, reply)?;
+        let (parent_id, uri): (Option<i32>, String) = comments::table
+            .inner_join(threads::table)
+            .select((comments::parent, threads::uri))
+            .filter(comments::id.eq(restored))
+            .first(conn)
+            .unwrap();
+        assert_eq!((parent_id, uri.as_str()), (None, "/restored"));
+        let trashed: i64 = comment_trash::table
+            .filter(comment_trash::cid.eq(reply))
+            .count()
+            .first(conn)
+            .unwrap();
+        assert_eq!(trashed, 0);
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that approving a held first comment trusts its commentor, whereas approving a
+/// comment which was held after being flagged does not.
+fn approved_commentors() {
+    use models::comments::Comment;
+    use models::{flags, trusted};
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/approvals");
+        let first = add_comment(conn, tid, None, 1, "newcomer", "192.0.2.1", 2);
+        let flagged = add_comment(conn, tid, None, 0, "troll", "192.0.2.2", 1);
+        flags::insert(conn, flagged, "192.0.2.3", "salt", "Spam")?;
+        assert!(flags::enforce_limit(conn, flagged, 1, 1)?);
+
+        Comment::approve(conn, flagged)?;
+        assert!(Comment::is_live(conn, flagged)?);
+        assert!(!trusted::contains(conn, "troll")?);
+
+        Comment::approve(conn, first)?;
+        assert!(Comment::is_live(conn, first)?);
+        assert!(trusted::contains(conn, "newcomer")?);
+
+        //Live comments aren't awaiting approval
+        assert!(Comment::approve(conn, first).is_err());
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that verifying an email address only vouches for the browser it was verified from,
+/// so typing in a verified address elsewhere is not enough to skip verification, nor does it
+/// publish comments held for that address which were sent from elsewhere.
+fn verified_browsers() {
+    use cipher::Cipher;
+    use models::emails;
+    use schema::comments;
+
+    let cipher = Cipher::new("secret");
+    rolled_back(|conn| {
+        let address = "owner@example.com";
+        let tid = add_thread(conn, "test", "/verification");
+        let mut held = Vec::new();
+        for browser in &["owner's browser", "impersonator's browser"] {
+            let cid = add_comment(conn, tid, None, 4, "commentor", "192.0.2.1", 1);
+            diesel::update(comments::table.filter(comments::id.eq(cid)))
+                .set((
+                    comments::email_index.eq(cipher.index(address)),
+                    comments::browser.eq(emails::browser_identity(browser)),
+                ))
+                .execute(conn)
+                .unwrap();
+            held.push(cid);
+        }
+        let token = emails::request_verification(conn, &cipher, address, "owner's browser")?;
+        assert_eq!(
+            emails::request_verification(conn, &cipher, address, "owner's browser")?,
+            token
+        );
+        assert!(!emails::is_verified(conn, &cipher, address, Some("owner's browser"))?);
+
+        assert_eq!(emails::verify(conn, &token)?, 1);
+        let modes: Vec<i32> = held
+            .iter()
+            .map(|cid| {
+                comments::table
+                    .select(comments::mode)
+                    .filter(comments::id.eq(cid))
+                    .first(conn)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(modes, vec![0, 4]);
+        assert!(emails::is_verified(conn, &cipher, " Owner@Example.com", Some("owner's browser"))?);
+        assert!(!emails::is_verified(conn, &cipher, address, Some("another browser"))?);
+        assert!(!emails::is_verified(conn, &cipher, address, Some(""))?);
+        assert!(!emails::is_verified(conn, &cipher, address, None)?);
+
+        //Another browser is sent a link of its own
+        let other = emails::request_verification(conn, &cipher, address, "another browser")?;
+        assert_ne!(other, token);
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that the browser token is left out of the form details sent with notifications.
+fn form_debug_information() {
+    use data::FormInput;
+
+    let form = FormInput {
+        comment: "A comment.".to_string(),
+        parent: None,
+        name: Some("Jane".to_string()),
+        email: None,
+        url: None,
+        title: "A post".to_string(),
+        path: "/post".to_string(),
+        browser: Some("browser-token".to_string()),
+    };
+    let debug = format!("{:?}", form);
+    assert!(debug.contains("Jane"));
+    assert!(!debug.contains("browser-token"));
+}
+
+#[test]
+/// Checks that threads close automatically `close_after` days after their first comment,
+/// and that the admin can close them early or reopen them for good.
+fn closed_threads() {
+    use models::threads::{is_closed, set_closed};
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/closing");
+        add_comment(conn, tid, None, 0, "author", "192.0.2.1", 3 * 24 * 60);
+        add_comment(conn, tid, None, 0, "author", "192.0.2.1", 1);
+
+        assert!(!is_closed(conn, tid, 0)?);
+        assert!(!is_closed(conn, tid, 5)?);
+        assert!(is_closed(conn, tid, 2)?);
+
+        set_closed(conn, tid, false)?;
+        assert!(!is_closed(conn, tid, 2)?);
+        set_closed(conn, tid, true)?;
+        assert!(is_closed(conn, tid, 0)?);
+        assert!(is_closed(conn, tid, 5)?);
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that post paths are read from sitemaps, and that posts are verified against an
+/// allowlist or a pattern without contacting the blog.
+fn post_verification() {
+    use config::{Normalise, Verify, VerifyMethod};
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use verify::{sitemap_paths, Verifier};
+
+    let sitemap = "<urlset>
+        <url><loc>https://example.com/posts/one/</loc></url>
+        <url><LOC> http://example.com/search?q=a&amp;page=2 </LOC></url>
+        <url><loc>https://example.com</loc></url>
+    </urlset>";
+    assert_eq!(
+        sitemap_paths(sitemap),
+        vec!["/posts/one/", "/search?q=a&page=2", "/"]
+    );
+
+    let rules = Normalise {
+        trailing_slash: true,
+        index_files: vec!["index.html".to_string()],
+        query: true,
+        fragment: true,
+        percent_encoding: true,
+        lowercase: false,
+    };
+    let allowlist = env::temp_dir().join("oration-test-allowlist");
+    File::create(&allowlist)
+        .and_then(|mut file| file.write_all(b"# Posts\n/posts/one/\n\n  /about\n"))
+        .unwrap();
+    let verifier = Verifier::new(&Verify {
+        method: VerifyMethod::Allowlist,
+        sitemap: None,
+        allowlist: Some(allowlist.to_string_lossy().into_owned()),
+        pattern: None,
+        cache_ttl: 60,
+    }).unwrap();
+    let host = "http://example.com";
+    assert!(verifier.verify(host, "/posts/one", &rules).is_ok());
+    assert!(verifier.verify(host, "/posts/one/index.html", &rules).is_ok());
+    assert!(verifier.verify(host, "/about/", &rules).is_ok());
+    assert!(verifier.verify(host, "/posts/two/", &rules).is_err());
+    //Comments in the allowlist would otherwise list the root of the blog
+    assert!(verifier.verify(host, "/", &rules).is_err());
+
+    let verifier = Verifier::new(&Verify {
+        method: VerifyMethod::Pattern,
+        sitemap: None,
+        allowlist: None,
+        pattern: Some("^/posts/[a-z-]+$".to_string()),
+        cache_ttl: 60,
+    }).unwrap();
+    assert!(verifier.verify(host, "/posts/hello-world/", &rules).is_ok());
+    assert!(verifier.verify(host, "/posts/hello-world/?page=2", &rules).is_ok());
+    assert!(verifier.verify(host, "/admin", &rules).is_err());
+    assert!(
+        Verifier::new(&Verify {
+            method: VerifyMethod::Pattern,
+            sitemap: None,
+            allowlist: None,
+            pattern: Some("(unclosed".to_string()),
+            cache_ttl: 60,
+        }).is_err()
+    );
+}
+
+#[test]
+/// Checks that comments are looked up on the site they were left on, so requests claiming to
+/// come from another site can't apply its settings to them.
+fn comment_sites() {
+    use models::comments::Comment;
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "other", "/sites");
+        let cid = add_comment(conn, tid, None, 0, "author", "192.0.2.1", 1);
+        assert_eq!(Comment::site(conn, cid)?, "other");
+        assert!(Comment::site(conn, -1).is_err());
+        Ok(())
+    });
+
+    let client = Client::new(rocket().0).expect("valid rocket instance");
+    for uri in &["/oration/like?id=-1", "/oration/dislike?id=-1"] {
+        let response = client.post(*uri).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
+
+#[test]
+/// Checks that threads are listed in each sort order, a page of comments at a time, and that
+/// replies below the depth limit are counted rather than listed.
+fn comment_listings() {
+    use data::SortOrder;
+    use models::comments::{Listing, NestedComment};
+    use schema::comments;
+
+    let listing = |sort, parent, page, page_size, depth| Listing {
+        sort,
+        parent,
+        page,
+        page_size,
+        depth,
+    };
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/listings");
+        let a = add_comment(conn, tid, None, 0, "one", "192.0.2.1", 30);
+        let a1 = add_comment(conn, tid, Some(a), 0, "two", "192.0.2.2", 25);
+        let a1x = add_comment(conn, tid, Some(a1), 0, "one", "192.0.2.1", 24);
+        let a2 = add_comment(conn, tid, Some(a), 0, "three", "192.0.2.3", 22);
+        let b = add_comment(conn, tid, None, 0, "two", "192.0.2.2", 20);
+        let c = add_comment(conn, tid, None, 0, "three", "192.0.2.3", 10);
+        for &(id, likes, dislikes) in &[(b, 5, 0), (c, 1, 3)] {
+            diesel::update(comments::table.filter(comments::id.eq(id)))
+                .set((comments::likes.eq(likes), comments::dislikes.eq(dislikes)))
+                .execute(conn)
+                .unwrap();
+        }
+        let ids = |list: &[i32]| list.iter().map(|&id| i64::from(id)).collect::<Vec<_>>();
+        let list = |listing: &Listing| {
+            NestedComment::list(conn, tid, "192.0.2.9", "192.0.2.9", None, listing)
+        };
+
+        let page = list(&listing(SortOrder::Oldest, None, 0, 0, None))?;
+        assert_eq!(listed_ids(&page.comments), ids(&[a, a1, a1x, a2, b, c]));
+        assert_eq!((page.total, page.next_page), (3, None));
+        let page = list(&listing(SortOrder::Newest, None, 0, 0, None))?;
+        assert_eq!(listed_ids(&page.comments), ids(&[c, b, a, a2, a1, a1x]));
+        let page = list(&listing(SortOrder::Top, None, 0, 0, None))?;
+        assert_eq!(listed_ids(&page.comments), ids(&[b, a, a1, a1x, a2, c]));
+
+        let page = list(&listing(SortOrder::Oldest, None, 0, 2, None))?;
+        assert_eq!(listed_ids(&page.comments), ids(&[a, a1, a1x, a2, b]));
+        assert_eq!((page.total, page.next_page), (3, Some(1)));
+        let page = list(&listing(SortOrder::Oldest, None, 1, 2, None))?;
+        assert_eq!(listed_ids(&page.comments), ids(&[c]));
+        assert_eq!((page.total, page.next_page), (3, None));
+        let page = list(&listing(SortOrder::Oldest, None, 5, 2, None))?;
+        assert!(page.comments.is_empty());
+
+        let page = list(&listing(SortOrder::Oldest, None, 0, 1, Some(0)))?;
+        let listed = serde_json::to_value(&page.comments).unwrap();
+        assert_eq!(listed_ids(&page.comments), ids(&[a]));
+        assert_eq!(listed[0]["more_replies"], 2);
+        let page = list(&listing(SortOrder::Oldest, None, 0, 1, Some(1)))?;
+        let listed = serde_json::to_value(&page.comments).unwrap();
+        assert_eq!(listed_ids(&page.comments), ids(&[a, a1, a2]));
+        assert_eq!(listed[0]["more_replies"], 0);
+        assert_eq!(listed[0]["children"][0]["more_replies"], 1);
+
+        let page = list(&listing(SortOrder::Oldest, Some(a), 0, 0, None))?;
+        assert_eq!(listed_ids(&page.comments), ids(&[a1, a1x, a2]));
+        assert_eq!((page.total, page.next_page), (2, None));
+        let page = list(&listing(SortOrder::Newest, Some(a), 0, 1, None))?;
+        assert_eq!(listed_ids(&page.comments), ids(&[a2]));
+        assert_eq!((page.total, page.next_page), (2, Some(1)));
+        assert!(list(&listing(SortOrder::Oldest, Some(-1), 0, 0, None)).is_err());
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that voting twice the same way retracts a vote, that voting the other way changes
+/// it, that the tally of the comment follows along, and that hidden comments can't be voted on.
+fn vote_casting() {
+    use models::comments::Comment;
+    use models::votes;
+    use schema::comments;
+    use schema::votes as vote_rows;
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/votes");
+        let cid = add_comment(conn, tid, None, 0, "author", "192.0.2.1", 1);
+        let tally = || {
+            comments::table
+                .select((comments::likes, comments::dislikes))
+                .filter(comments::id.eq(cid))
+                .first::<(Option<i32>, Option<i32>)>(conn)
+                .unwrap()
+        };
+        let current = |voter: &str| {
+            vote_rows::table
+                .select(vote_rows::upvote)
+                .filter(vote_rows::cid.eq(cid).and(vote_rows::voter.eq(voter)))
+                .first::<bool>(conn)
+                .optional()
+                .unwrap()
+        };
+
+        assert_eq!(votes::cast(conn, cid, "first", true)?, Some(true));
+        assert_eq!(votes::cast(conn, cid, "second", true)?, Some(true));
+        assert_eq!(tally(), (Some(2), Some(0)));
+        assert_eq!(votes::cast(conn, cid, "first", false)?, Some(false));
+        assert_eq!(tally(), (Some(1), Some(1)));
+        assert_eq!(current("first"), Some(false));
+        assert_eq!(votes::cast(conn, cid, "first", false)?, None);
+        assert_eq!(tally(), (Some(1), Some(0)));
+        assert_eq!(current("first"), None);
+
+        //Readers vote under their salted address
+        assert_eq!(Comment::vote(conn, cid, "192.0.2.2", "salt", true)?, Some(true));
+        assert_eq!(Comment::vote(conn, cid, "192.0.2.2", "salt", true)?, None);
+        assert_eq!(tally(), (Some(1), Some(0)));
+
+        //Hidden comments can't be voted on
+        for &mode in &[1, 3, 4, 5] {
+            let hidden = add_comment(conn, tid, None, mode, "author", "192.0.2.1", 1);
+            match votes::cast(conn, hidden, "first", true) {
+                Err(errors::Error(errors::ErrorKind::NoComment(_), _)) => (),
+                other => panic!("expected a hidden comment to be missing, got {:?}", other),
+            }
+        }
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that each reader counts once towards each reaction, that hidden comments can't be
+/// reacted to, and that a post keeps its thread while it has reactions, even without comments.
+fn comment_reactions() {
+    use models::reactions;
+    use schema::{comments, threads};
+
+    rolled_back(|conn| {
+        let tid = add_thread(conn, "test", "/reactions");
+        let cid = add_comment(conn, tid, None, 0, "author", "192.0.2.1", 2);
+        let held = add_comment(conn, tid, None, 1, "newcomer", "192.0.2.2", 1);
+
+        reactions::add_to_comment(conn, cid, "first", "👍")?;
+        reactions::add_to_comment(conn, cid, "first", "👍")?;
+        reactions::add_to_comment(conn, cid, "first", "❤️")?;
+        let tally = reactions::add_to_comment(conn, cid, "second", "👍")?;
+        assert_eq!(tally.get("👍"), Some(&2));
+        assert_eq!(tally.get("❤️"), Some(&1));
+        let tally = reactions::remove_from_comment(conn, cid, "first", "👍")?;
+        assert_eq!(tally.get("👍"), Some(&1));
+
+        assert!(reactions::add_to_comment(conn, held, "first", "👍").is_err());
+        assert!(reactions::remove_from_comment(conn, held, "first", "👍").is_err());
+
+        reactions::add_to_thread(conn, tid, "first", "🤔")?;
+        diesel::delete(comments::table.filter(comments::tid.eq(tid)))
+            .execute(conn)
+            .unwrap();
+        let remaining: i64 = threads::table
+            .filter(threads::id.eq(tid))
+            .count()
+            .first(conn)
+            .unwrap();
+        assert_eq!(remaining, 1);
+        assert_eq!(reactions::for_thread(conn, tid)?.get("🤔"), Some(&1));
+        Ok(())
+    });
+
+    let client = Client::new(rocket().0).expect("valid rocket instance");
+    let response = client.post("/oration/react?id=1&reaction=nope").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+/// Checks that counts are cached until they expire or the cache is cleared.
+fn count_cache_expiry() {
+    use counts::CountCache;
+
+    let cache = CountCache::new(60);
+    assert_eq!(cache.get("test", "/post"), None);
+    cache.insert("test", "/post", 3);
+    assert_eq!(cache.get("test", "/post"), Some(3));
+    assert_eq!(cache.get("other", "/post"), None);
+    cache.clear();
+    assert_eq!(cache.get("test", "/post"), None);
+
+    let expired = CountCache::new(0);
+    expired.insert("test", "/post", 3);
+    assert_eq!(expired.get("test", "/post"), None);
+}
+
+#[test]
+/// Checks that only live comments are counted, and that many threads are found by their URIs
+/// just as they are one at a time: by their normalised URI, their stored URI or an alias.
+fn thread_counts() {
+    use config::Normalise;
+    use models::comments::Comment;
+    use models::threads::{get_id, get_ids};
+    use schema::thread_aliases;
+
+    let rules = Normalise {
+        trailing_slash: true,
+        index_files: vec!["index.html".to_string()],
+        query: true,
+        fragment: true,
+        percent_encoding: true,
+        lowercase: false,
+    };
+    rolled_back(|conn| {
+        let normal = add_thread(conn, "test", "/counted");
+        //Stored before the normalisation rules changed
+        let stored = add_thread(conn, "test", "/stored/index.html");
+        let other = add_thread(conn, "other", "/counted");
+        diesel::insert_into(thread_aliases::table)
+            .values((
+                thread_aliases::site.eq("test"),
+                thread_aliases::uri.eq("/moved"),
+                thread_aliases::tid.eq(normal),
+            ))
+            .execute(conn)
+            .unwrap();
+        for &(tid, mode) in &[(normal, 0), (normal, 0), (normal, 1), (normal, 3), (stored, 2)] {
+            add_comment(conn, tid, None, mode, "author", "192.0.2.1", 1);
+        }
+        add_comment(conn, other, None, 0, "author", "192.0.2.1", 1);
+
+        let counts = Comment::counts(conn, &[normal, stored, other])?;
+        assert_eq!(counts.get(&normal), Some(&2));
+        assert_eq!(counts.get(&stored), None);
+        assert_eq!(counts.get(&other), Some(&1));
+        assert!(Comment::counts(conn, &[])?.is_empty());
+        assert_eq!(Comment::count(conn, normal)?, 2);
+
+        let uris: Vec<String> = [
+            "/counted/",
+            "/counted/index.html?page=2",
+            "/stored/index.html",
+            "/moved/",
+            "/missing",
+        ].iter()
+            .map(|uri| uri.to_string())
+            .collect();
+        let ids = get_ids(conn, "test", &uris, &rules)?;
+        for uri in &uris {
+            assert_eq!(ids.get(uri).cloned(), get_id(conn, "test", uri, &rules).ok());
+        }
+        assert_eq!(ids.get("/moved/"), Some(&normal));
+        assert_eq!(ids.get("/stored/index.html"), Some(&stored));
+        assert_eq!(ids.get("/missing"), None);
+        Ok(())
+    });
+}
+
+#[test]
+/// Checks that merging threads moves the reactions of the removed thread, keeping a single
+/// reaction from readers who reacted to both.
+fn merged_thread_reactions() {
+    use models::{reactions, threads};
+
+    rolled_back(|conn| {
+        let from = add_thread(conn, "test", "/merged-from");
+        let into = add_thread(conn, "test", "/merged-into");
+        reactions::add_to_thread(conn, from, "both", "heart")?;
+        reactions::add_to_thread(conn, from, "first", "heart")?;
+        reactions::add_to_thread(conn, into, "both", "heart")?;
+
+        threads::merge(conn, from, into)?;
+        assert_eq!(reactions::for_thread(conn, into)?.get("heart"), Some(&2));
+        assert!(reactions::for_thread(conn, from)?.is_empty());
+        Ok(())
+    });
+}
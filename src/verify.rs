@@ -0,0 +1,173 @@
+use regex::Regex;
+use reqwest;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use config::{Normalise, Verify, VerifyMethod};
+use errors::*;
+use models::threads::normalise;
+
+/// Number of cached results above which expired entries are pruned.
+const CACHE_PRUNE: usize = 1024;
+
+/// Checks that posts exist on the blog before threads are created for them,
+/// so junk cannot be injected into the database through the comment form.
+pub struct Verifier {
+    /// Strategy used to verify posts.
+    method: VerifyMethod,
+    /// Location of the sitemap or allowlist, if the strategy requires one.
+    source: Option<String>,
+    /// Pattern posts must match, if the strategy requires one.
+    pattern: Option<Regex>,
+    /// How long verification results are trusted for.
+    ttl: Duration,
+    /// Previous verification results for each post url, and when they were obtained.
+    results: Mutex<HashMap<String, (bool, Instant)>>,
+    /// Paths listed in the sitemap or allowlist, and when they were read.
+    paths: Mutex<Option<(HashSet<String>, Instant)>>,
+}
+
+impl Verifier {
+    /// Builds a verifier from the `verify` section of the configuration file.
+    pub fn new(settings: &Verify) -> Result<Verifier> {
+        let source = match settings.method {
+            VerifyMethod::Sitemap => settings.sitemap.clone(),
+            VerifyMethod::Allowlist => settings.allowlist.clone(),
+            _ => None,
+        };
+        let pattern = match (settings.method, &settings.pattern) {
+            (VerifyMethod::Pattern, &Some(ref pattern)) => {
+                Some(Regex::new(pattern).chain_err(|| ErrorKind::InvalidVerifyPattern)?)
+            }
+            _ => None,
+        };
+        Ok(Verifier {
+            method: settings.method,
+            source,
+            pattern,
+            ttl: Duration::from_secs(settings.cache_ttl),
+            results: Mutex::new(HashMap::new()),
+            paths: Mutex::new(None),
+        })
+    }
+
+    /// Checks that the `path` posted actually exists on the `host`.
+    /// Results are cached, so the blog isn't consulted for every new thread.
+    pub fn verify(&self, host: &str, path: &str, rules: &Normalise) -> Result<()> {
+        let path = normalise(path, rules);
+        let url = format!("{}{}", host.trim_right_matches('/'), path);
+        let cached = self.results.lock().ok().and_then(|results| {
+            results.get(&url).and_then(|&(found, checked)| {
+                if checked.elapsed() < self.ttl {
+                    Some(found)
+                } else {
+                    None
+                }
+            })
+        });
+
+        let found = match cached {
+            Some(found) => found,
+            None => {
+                let found = match self.method {
+                    VerifyMethod::Http => http_check(host, &path)?,
+                    VerifyMethod::Sitemap | VerifyMethod::Allowlist => self.listed(&path, rules)?,
+                    VerifyMethod::Pattern => self
+                        .pattern
+                        .as_ref()
+                        .map_or(false, |pattern| pattern.is_match(&path)),
+                    VerifyMethod::None => true,
+                };
+                if let Ok(mut results) = self.results.lock() {
+                    if results.len() > CACHE_PRUNE {
+                        let ttl = self.ttl;
+                        results.retain(|_, &mut (_, checked)| checked.elapsed() < ttl);
+                    }
+                    results.insert(url, (found, Instant::now()));
+                }
+                found
+            }
+        };
+
+        if found {
+            Ok(())
+        } else {
+            Err(ErrorKind::PathCheckFailed.into())
+        }
+    }
+
+    /// Checks if a normalised `path` is listed in the sitemap or allowlist, reading
+    /// the list again if it has expired.
+    fn listed(&self, path: &str, rules: &Normalise) -> Result<bool> {
+        let mut paths = self.paths.lock().map_err(|_| ErrorKind::VerifySource)?;
+        let expired = match *paths {
+            Some((_, read)) => read.elapsed() >= self.ttl,
+            None => true,
+        };
+        if expired {
+            let source = self.source.as_ref().ok_or(ErrorKind::VerifySource)?;
+            let contents = read_source(source)?;
+            let listed = match self.method {
+                VerifyMethod::Sitemap => sitemap_paths(&contents),
+                _ => contents
+                    .lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|line| line.to_string())
+                    .collect(),
+            };
+            let listed = listed.iter().map(|p| normalise(p, rules)).collect();
+            *paths = Some((listed, Instant::now()));
+        }
+        Ok(paths
+            .as_ref()
+            .map_or(false, |&(ref listed, _)| listed.contains(path)))
+    }
+}
+
+/// Requests the `path` from the `host`, which must respond with a 200.
+fn http_check(host: &str, path: &str) -> Result<bool> {
+    // We use reqwest to handle the request for now, but may drop down to hyper later on.
+    let res = reqwest::get(&format!("{}{}", host.trim_right_matches('/'), path))
+        .chain_err(|| ErrorKind::Request)?;
+
+    Ok(res.status() == reqwest::StatusCode::Ok)
+}
+
+/// Reads a sitemap or allowlist from a url or a file on disk.
+fn read_source(source: &str) -> Result<String> {
+    let mut contents = String::new();
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let mut res = reqwest::get(source).chain_err(|| ErrorKind::Request)?;
+        if res.status() != reqwest::StatusCode::Ok {
+            return Err(ErrorKind::VerifySource.into());
+        }
+        contents = res.text().chain_err(|| ErrorKind::VerifySource)?;
+    } else {
+        File::open(source)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .chain_err(|| ErrorKind::VerifySource)?;
+    }
+    Ok(contents)
+}
+
+/// Extracts the path of every `<loc>` entry in a sitemap.
+pub fn sitemap_paths(sitemap: &str) -> Vec<String> {
+    lazy_static! {
+        static ref LOC: Regex = Regex::new(r"(?is)<loc>\s*(.*?)\s*</loc>").unwrap();
+    }
+    LOC.captures_iter(sitemap)
+        .filter_map(|caps| caps.get(1))
+        .map(|loc| {
+            let url = loc.as_str().replace("&amp;", "&");
+            //Drop the protocol and domain, leaving only the path
+            let without_scheme = url.find("://").map_or(&url[..], |idx| &url[idx + 3..]);
+            match without_scheme.find('/') {
+                Some(idx) => without_scheme[idx..].to_string(),
+                None => "/".to_string(),
+            }
+        }).collect()
+}